@@ -0,0 +1,1109 @@
+//! Sinclair BASIC programs and variables, as saved by the ROM
+
+use crate::tap::{
+    self, Block, BlockParams, FlagEnum, Header, HeaderTypeEnum, ProgramParams, NO_AUTOSTART,
+};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Read};
+
+/// Keywords of the 48K ROM, for the token codes 0xA5 to 0xFF
+pub const TOKENS: [&str; 91] = [
+    "RND",
+    "INKEY$",
+    "PI",
+    "FN",
+    "POINT",
+    "SCREEN$",
+    "ATTR",
+    "AT",
+    "TAB",
+    "VAL$",
+    "CODE",
+    "VAL",
+    "LEN",
+    "SIN",
+    "COS",
+    "TAN",
+    "ASN",
+    "ACS",
+    "ATN",
+    "LN",
+    "EXP",
+    "INT",
+    "SQR",
+    "SGN",
+    "ABS",
+    "PEEK",
+    "IN",
+    "USR",
+    "STR$",
+    "CHR$",
+    "NOT",
+    "BIN",
+    "OR",
+    "AND",
+    "<=",
+    ">=",
+    "<>",
+    "LINE",
+    "THEN",
+    "TO",
+    "STEP",
+    "DEF FN",
+    "CAT",
+    "FORMAT",
+    "MOVE",
+    "ERASE",
+    "OPEN #",
+    "CLOSE #",
+    "MERGE",
+    "VERIFY",
+    "BEEP",
+    "CIRCLE",
+    "INK",
+    "PAPER",
+    "FLASH",
+    "BRIGHT",
+    "INVERSE",
+    "OVER",
+    "OUT",
+    "LPRINT",
+    "LLIST",
+    "STOP",
+    "READ",
+    "DATA",
+    "RESTORE",
+    "NEW",
+    "BORDER",
+    "CONTINUE",
+    "DIM",
+    "REM",
+    "FOR",
+    "GO TO",
+    "GO SUB",
+    "INPUT",
+    "LOAD",
+    "LIST",
+    "LET",
+    "PAUSE",
+    "NEXT",
+    "POKE",
+    "PRINT",
+    "PLOT",
+    "RUN",
+    "SAVE",
+    "RANDOMIZE",
+    "IF",
+    "CLS",
+    "DRAW",
+    "CLEAR",
+    "RETURN",
+    "COPY",
+];
+
+/// Code of the first keyword token
+pub const FIRST_TOKEN: u8 = 0xA5;
+
+/// Marks the 5 byte binary form of a number literal in a program line
+pub const NUMBER_MARKER: u8 = 0x0E;
+
+/// Ends every program line
+pub const END_OF_LINE: u8 = 0x0D;
+
+/// Names of the colour control codes 0x10 to 0x15, each followed by one byte
+const COLOUR_CODES: [&str; 6] = ["INK", "PAPER", "FLASH", "BRIGHT", "INVERSE", "OVER"];
+const AT_CODE: u8 = 0x16;
+const TAB_CODE: u8 = 0x17;
+
+/// Block graphics 0x81 to 0x8F; 0x80 is blank and written as an escape
+const BLOCK_GRAPHICS: [char; 15] = [
+    '▝', '▘', '▀', '▗', '▐', '▚', '▜', '▖', '▞', '▌', '▛', '▄', '▟', '▙', '█',
+];
+
+/// Converts the 5 byte floating point form of a number. Whole numbers
+/// between -65535 and 65535 are usually stored as a small integer instead,
+/// with a zero exponent.
+pub fn decode_number(bytes: &[u8; 5]) -> f64 {
+    if bytes[0] == 0 {
+        let value = u16::from_le_bytes([bytes[2], bytes[3]]) as i32;
+        return if bytes[1] == 0xFF {
+            value - 65536
+        } else {
+            value
+        } as f64;
+    }
+
+    let mantissa = u32::from_be_bytes([bytes[1] | 0x80, bytes[2], bytes[3], bytes[4]]);
+    let value = mantissa as f64 * 2f64.powi(bytes[0] as i32 - 128 - 32);
+    if bytes[1] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Formats a number the way `PRINT` does: up to 8 significant digits, with
+/// an exponent for very large and very small values
+pub fn format_number(value: f64) -> String {
+    if value == value.trunc() && value.abs() < 1e9 {
+        return format!("{}", value as i64);
+    }
+
+    // Rounding through the exponent form keeps 8 significant digits
+    let rounded: f64 = format!("{:.7e}", value).parse().unwrap_or(value);
+    if rounded.abs() >= 1e9 || rounded.abs() < 1e-5 {
+        let text = format!("{:e}", rounded);
+        match text.split_once('e') {
+            Some((mantissa, exponent)) if exponent.starts_with('-') => {
+                format!("{}E{}", mantissa, exponent)
+            }
+            Some((mantissa, exponent)) => format!("{}E+{}", mantissa, exponent),
+            None => text,
+        }
+    } else {
+        format!("{}", rounded)
+    }
+}
+
+/// Converts a number to the 5 byte form, using the small integer form for
+/// whole numbers that fit it like the ROM does
+pub fn encode_number(value: f64) -> io::Result<[u8; 5]> {
+    if value == value.trunc() && value.abs() <= 65535.0 {
+        let sign = if value < 0.0 { 0xFF } else { 0x00 };
+        let [lo, hi] = ((value as i32 + 65536) as u16).to_le_bytes();
+        return Ok([0, sign, lo, hi, 0]);
+    }
+
+    let magnitude = value.abs();
+    let mut exponent = magnitude.log2().floor() as i32 + 1;
+    let mut mantissa = (magnitude * 2f64.powi(32 - exponent)).round() as u64;
+    // Correct for the rounding of log2 and of the mantissa itself
+    while mantissa >= 1 << 32 {
+        mantissa >>= 1;
+        exponent += 1;
+    }
+    while mantissa < 1 << 31 {
+        mantissa <<= 1;
+        exponent -= 1;
+    }
+
+    match exponent + 128 {
+        // Too small to store, the ROM rounds these to zero
+        ..=0 => Ok([0; 5]),
+        256.. => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Number too big: {}", value),
+        )),
+        exponent => {
+            let [b1, b2, b3, b4] = (mantissa as u32).to_be_bytes();
+            let sign = if value < 0.0 { 0x80 } else { 0x00 };
+            Ok([exponent as u8, b1 & 0x7F | sign, b2, b3, b4])
+        }
+    }
+}
+
+fn read_number<R: Read>(reader: &mut R) -> io::Result<f64> {
+    let mut bytes = [0; 5];
+    reader.read_exact(&mut bytes)?;
+    Ok(decode_number(&bytes))
+}
+
+/// Renders text in the Spectrum character set, such as a string value or a
+/// file name. Keywords are spelled out, everything without a printable form
+/// is written as an escape in braces.
+pub fn to_text(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        index += push_char(&mut text, &bytes[index..]);
+    }
+    text
+}
+
+// Appends the character or control sequence at the start of `bytes` and
+// returns the number of bytes it took
+fn push_char(text: &mut String, bytes: &[u8]) -> usize {
+    let code = bytes[0];
+    match code {
+        0x10..=0x15 if bytes.len() >= 2 => {
+            text.push_str(&format!(
+                "{{{} {}}}",
+                COLOUR_CODES[(code - 0x10) as usize],
+                bytes[1]
+            ));
+            2
+        }
+        AT_CODE if bytes.len() >= 3 => {
+            text.push_str(&format!("{{AT {},{}}}", bytes[1], bytes[2]));
+            3
+        }
+        TAB_CODE if bytes.len() >= 3 => {
+            text.push_str(&format!(
+                "{{TAB {}}}",
+                u16::from_le_bytes([bytes[1], bytes[2]])
+            ));
+            3
+        }
+        0x5E => {
+            text.push('↑');
+            1
+        }
+        0x60 => {
+            text.push('£');
+            1
+        }
+        0x7F => {
+            text.push('©');
+            1
+        }
+        0x20..=0x7E if code != b'{' => {
+            text.push(code as char);
+            1
+        }
+        0x81..=0x8F => {
+            text.push(BLOCK_GRAPHICS[(code - 0x81) as usize]);
+            1
+        }
+        0x90..=0xA4 => {
+            text.push_str(&format!("{{UDG {}}}", (b'A' + code - 0x90) as char));
+            1
+        }
+        FIRST_TOKEN..=0xFF => {
+            push_token(text, code);
+            1
+        }
+        _ => {
+            text.push_str(&format!("{{0x{:02x}}}", code));
+            1
+        }
+    }
+}
+
+/// First token the ROM prints with a space before it; the functions below it
+/// follow their operand directly, as in `LOAD ""CODE`
+const FIRST_SPACED_TOKEN: u8 = 0xC5;
+
+// Keywords from OR onwards that start with a letter get a space before them
+fn leading_space(code: u8) -> bool {
+    code >= FIRST_SPACED_TOKEN
+        && TOKENS[(code - FIRST_TOKEN) as usize].starts_with(|c: char| c.is_ascii_alphabetic())
+}
+
+// Keywords ending in a letter or `$` get a space after them, except RND,
+// INKEY$ and PI which take no operand
+fn trailing_space(code: u8) -> bool {
+    code >= FIRST_TOKEN + 3
+        && TOKENS[(code - FIRST_TOKEN) as usize]
+            .ends_with(|c: char| c.is_ascii_alphabetic() || c == '$')
+}
+
+// Keywords get the spaces the ROM prints around them, but never two spaces
+// before one
+fn push_token(text: &mut String, code: u8) {
+    if leading_space(code) && !text.is_empty() && !text.ends_with(' ') {
+        text.push(' ');
+    }
+    text.push_str(TOKENS[(code - FIRST_TOKEN) as usize]);
+    if trailing_space(code) {
+        text.push(' ');
+    }
+}
+
+// Start of the number literal at the end of the listing text, if any
+fn literal_start(text: &str) -> Option<usize> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut start = None;
+    for (i, &(offset, c)) in chars.iter().enumerate().rev() {
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let part = c.is_ascii_digit()
+            || c == '.'
+            || (matches!(c, 'e' | 'E')
+                && next.is_some_and(|n| n.is_ascii_digit() || n == '+' || n == '-'))
+            || (matches!(c, '+' | '-') && i > 0 && matches!(chars[i - 1].1, 'e' | 'E'));
+        if !part {
+            break;
+        }
+        start = Some(offset);
+    }
+    start
+}
+
+// Value of the literal the ROM turned into a hidden number, following BIN
+// when it is a binary literal
+fn literal_value(text: &str) -> Option<f64> {
+    let start = literal_start(text)?;
+    let literal = &text[start..];
+    if text[..start].trim_end().ends_with("BIN") {
+        u32::from_str_radix(literal, 2).ok().map(f64::from)
+    } else {
+        literal.parse().ok()
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Code of a character that stands for itself in the listing
+fn char_code(c: char) -> Option<u8> {
+    match c {
+        '↑' | '^' => Some(0x5E),
+        '£' => Some(0x60),
+        '©' => Some(0x7F),
+        ' '..='~' if c != '{' => Some(c as u8),
+        _ => BLOCK_GRAPHICS
+            .iter()
+            .position(|&graphic| graphic == c)
+            .map(|index| 0x81 + index as u8),
+    }
+}
+
+// Bytes of an escape in braces, the inverse of what `push_char` writes
+fn escape_bytes(escape: &str) -> io::Result<Vec<u8>> {
+    let bad = || invalid(format!("Invalid escape {{{}}}", escape));
+    let number = |text: &str| text.trim().parse::<u16>().map_err(|_| bad());
+    let byte = |text: &str| text.trim().parse::<u8>().map_err(|_| bad());
+
+    if let Some(hex) = escape.strip_prefix("0x") {
+        return Ok(vec![u8::from_str_radix(hex, 16).map_err(|_| bad())?]);
+    }
+
+    let (name, argument) = escape.split_once(' ').ok_or_else(bad)?;
+    if let Some(index) = COLOUR_CODES.iter().position(|&code| code == name) {
+        return Ok(vec![0x10 + index as u8, byte(argument)?]);
+    }
+
+    match name {
+        "AT" => {
+            let (row, column) = argument.split_once(',').ok_or_else(bad)?;
+            Ok(vec![AT_CODE, byte(row)?, byte(column)?])
+        }
+        "TAB" => {
+            let [lo, hi] = number(argument)?.to_le_bytes();
+            Ok(vec![TAB_CODE, lo, hi])
+        }
+        "UDG" => match argument.as_bytes() {
+            [letter @ b'A'..=b'U'] => Ok(vec![0x90 + letter - b'A']),
+            _ => Err(bad()),
+        },
+        _ => Err(bad()),
+    }
+}
+
+// The keyword at the start of `chars`, as its token and the number of
+// characters it takes. Keywords only count as whole words, so variable names
+// that contain one are left alone.
+fn match_keyword(chars: &[char], previous: Option<char>) -> Option<(u8, usize)> {
+    if previous.is_some_and(|c| c.is_ascii_alphabetic()) && chars[0].is_ascii_alphabetic() {
+        return None;
+    }
+
+    let aliases = [("GOTO", "GO TO"), ("GOSUB", "GO SUB")];
+    let candidates = TOKENS
+        .iter()
+        .map(|&keyword| (keyword, keyword))
+        .chain(aliases);
+
+    candidates
+        .filter(|(spelling, _)| {
+            let len = spelling.chars().count();
+            chars.len() >= len
+                && chars[..len].iter().copied().eq(spelling.chars())
+                && !(spelling.ends_with(|c: char| c.is_ascii_alphabetic())
+                    && chars.get(len).is_some_and(|c| c.is_ascii_alphabetic()))
+        })
+        .max_by_key(|(spelling, _)| spelling.len())
+        .map(|(spelling, keyword)| {
+            let index = TOKENS.iter().position(|&token| token == keyword).unwrap();
+            (FIRST_TOKEN + index as u8, spelling.chars().count())
+        })
+}
+
+// Length of the number literal at the start of `chars`, binary if it follows
+// BIN
+fn literal_len(chars: &[char], binary: bool) -> usize {
+    if binary {
+        return chars.iter().take_while(|&&c| c == '0' || c == '1').count();
+    }
+
+    let digits = |from: usize| {
+        chars[from.min(chars.len())..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count()
+    };
+    let mut len = digits(0);
+    if chars.get(len) == Some(&'.') {
+        len += 1 + digits(len + 1);
+    }
+    if matches!(chars.get(len), Some('e' | 'E')) {
+        let sign = matches!(chars.get(len + 1), Some('+' | '-')) as usize;
+        let exponent = digits(len + 1 + sign);
+        if exponent > 0 {
+            len += 1 + sign + exponent;
+        }
+    }
+    len
+}
+
+/// Tokenizes the text of a program line, the inverse of the listing: keywords
+/// become tokens, number literals get their hidden 5 byte form and escapes in
+/// braces are turned back into the codes they stand for. Text in quotes and
+/// after REM is kept as it is.
+pub fn tokenize(text: &str) -> io::Result<Vec<u8>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut bytes = Vec::new();
+    let mut index = 0;
+    let mut in_string = false;
+    let mut in_rem = false;
+    let mut after_bin = false;
+    // DEF FN leaves a 5 byte slot after each argument name for its value
+    let mut def_fn = false;
+    let mut in_arguments = false;
+    // Where the hidden number of the last literal starts, for `{=value}`
+    let mut last_number = None;
+
+    while index < chars.len() {
+        let c = chars[index];
+
+        if c == '{' {
+            let len = chars[index..]
+                .iter()
+                .position(|&c| c == '}')
+                .ok_or_else(|| invalid("Unterminated escape".to_string()))?;
+            let escape: String = chars[index + 1..index + len].iter().collect();
+            index += len + 1;
+
+            if let Some(value) = escape.strip_prefix('=') {
+                let value: f64 = value
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid number {{{}}}", escape)))?;
+                let start = last_number.unwrap_or(bytes.len());
+                bytes.truncate(start);
+                bytes.push(NUMBER_MARKER);
+                bytes.extend_from_slice(&encode_number(value)?);
+            } else {
+                bytes.extend(escape_bytes(&escape)?);
+            }
+            last_number = None;
+            continue;
+        }
+        last_number = None;
+
+        if in_string || in_rem {
+            in_string &= c != '"';
+        } else if c == '"' {
+            in_string = true;
+        } else if let Some((token, len)) =
+            match_keyword(&chars[index..], chars.get(index.wrapping_sub(1)).copied())
+        {
+            let keyword = TOKENS[(token - FIRST_TOKEN) as usize];
+            // Drop the spaces the listing puts around keywords
+            if leading_space(token) && bytes.last() == Some(&b' ') {
+                bytes.pop();
+            }
+            bytes.push(token);
+            index += len;
+            if trailing_space(token) && chars.get(index) == Some(&' ') {
+                index += 1;
+            }
+
+            in_rem = keyword == "REM";
+            after_bin = keyword == "BIN";
+            def_fn |= keyword == "DEF FN";
+            continue;
+        } else if (c.is_ascii_digit()
+            || (c == '.' && chars.get(index + 1).is_some_and(char::is_ascii_digit))
+            || after_bin)
+            && !chars
+                .get(index.wrapping_sub(1))
+                .is_some_and(|c| c.is_ascii_alphanumeric())
+        {
+            let len = literal_len(&chars[index..], after_bin);
+            let literal: String = chars[index..index + len].iter().collect();
+            let value = if after_bin {
+                u32::from_str_radix(&literal, 2)
+                    .map(f64::from)
+                    .unwrap_or(0.0)
+            } else {
+                literal
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid number {}", literal)))?
+            };
+
+            bytes.extend(literal.bytes());
+            last_number = Some(bytes.len());
+            bytes.push(NUMBER_MARKER);
+            bytes.extend_from_slice(&encode_number(value)?);
+            index += len;
+            after_bin = false;
+            continue;
+        } else if def_fn && c == '(' {
+            in_arguments = true;
+        } else if in_arguments && (c == ',' || c == ')') {
+            if bytes
+                .last()
+                .is_some_and(|&b| b.is_ascii_alphabetic() || b == b'$')
+            {
+                bytes.push(NUMBER_MARKER);
+                bytes.extend_from_slice(&[0; 5]);
+            }
+            if c == ')' {
+                def_fn = false;
+                in_arguments = false;
+            }
+        }
+
+        after_bin &= c == ' ';
+        bytes.push(
+            char_code(c).ok_or_else(|| invalid(format!("Character {} has no Spectrum code", c)))?,
+        );
+        index += 1;
+    }
+
+    Ok(bytes)
+}
+
+/// A one line loader for a block of code: makes room for it below `clear`,
+/// loads it and calls it at `usr`
+pub fn code_loader(clear: u16, usr: u16) -> io::Result<Program> {
+    Program::from_source(&format!(
+        "10 CLEAR {}: LOAD \"\"CODE : RANDOMIZE USR {}",
+        clear, usr
+    ))
+}
+
+/// One line of a BASIC program
+#[derive(Debug, Clone)]
+pub struct Line {
+    /// Line number, from 0 to 9999
+    pub number: u16,
+    /// The line as `LIST` shows it, with the hidden value of a number written
+    /// as `{=value}` after the literal when the two disagree
+    pub text: String,
+}
+
+impl Line {
+    /// Reads a line: number (big endian), length, contents and end of line
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let number = reader.read_u16::<BigEndian>()?;
+        let len = reader.read_u16::<LittleEndian>()? as usize;
+        let mut contents = vec![0; len];
+        reader
+            .read_exact(&mut contents)
+            .map_err(|_| Error::new(ErrorKind::UnexpectedEof, "Truncated BASIC line"))?;
+
+        if contents.last() == Some(&END_OF_LINE) {
+            contents.pop();
+        }
+
+        let mut text = String::new();
+        let mut index = 0;
+        while index < contents.len() {
+            if contents[index] == NUMBER_MARKER && index + 6 <= contents.len() {
+                let mut bytes = [0; 5];
+                bytes.copy_from_slice(&contents[index + 1..index + 6]);
+                let hidden = decode_number(&bytes);
+                // Numbers without a literal are the argument slots of DEF FN
+                let differs = literal_value(&text).is_some_and(|visible| {
+                    (visible - hidden).abs() > visible.abs().max(hidden.abs()) * 1e-8
+                });
+                if differs {
+                    text.push_str(&format!("{{={}}}", format_number(hidden)));
+                }
+                index += 6;
+            } else {
+                index += push_char(&mut text, &contents[index..]);
+            }
+        }
+
+        Ok(Line { number, text })
+    }
+}
+
+impl Line {
+    /// The line as stored in a program: number, length, tokenized text and
+    /// end of line
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut contents = tokenize(&self.text)?;
+        contents.push(END_OF_LINE);
+        let len = u16::try_from(contents.len())
+            .map_err(|_| invalid(format!("Line {} is too long", self.number)))?;
+
+        let mut bytes = Vec::with_capacity(contents.len() + 4);
+        bytes.extend_from_slice(&self.number.to_be_bytes());
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend(contents);
+        Ok(bytes)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:4} {}", self.number, self.text)
+    }
+}
+
+/// Elements of an array, stored in row-major order
+#[derive(Debug, Clone)]
+pub enum ArrayValues {
+    /// 5-byte floating point elements of a numeric array
+    Numbers(Vec<f64>),
+    /// Characters of a character array
+    Chars(Vec<u8>),
+}
+
+/// A numeric or character array, as found in the variables area and in
+/// array files saved with `SAVE "name" DATA`
+#[derive(Debug, Clone)]
+pub struct Array {
+    /// Size of each dimension
+    pub dimensions: Vec<u16>,
+    /// The elements
+    pub values: ArrayValues,
+}
+
+impl Array {
+    /// Reads the number of dimensions, their sizes and the elements. The
+    /// element count is checked against the bytes left in `reader` before
+    /// anything is allocated, so corrupt sizes can't exhaust memory.
+    pub fn from_bytes(reader: &mut &[u8], numeric: bool) -> io::Result<Self> {
+        let count = reader.read_u8()?;
+        let dimensions = (0..count)
+            .map(|_| reader.read_u16::<LittleEndian>())
+            .collect::<io::Result<Vec<u16>>>()?;
+        let element_size = if numeric { 5 } else { 1 };
+        let len = dimensions
+            .iter()
+            .try_fold(1usize, |len, &size| len.checked_mul(size as usize))
+            .filter(|&len| {
+                len.checked_mul(element_size)
+                    .is_some_and(|size| size <= reader.len())
+            })
+            .ok_or(Error::new(
+                ErrorKind::InvalidData,
+                "Array dimensions exceed the data",
+            ))?;
+
+        let values = if numeric {
+            ArrayValues::Numbers(
+                (0..len)
+                    .map(|_| read_number(reader))
+                    .collect::<io::Result<_>>()?,
+            )
+        } else {
+            let mut chars = vec![0; len];
+            reader.read_exact(&mut chars)?;
+            ArrayValues::Chars(chars)
+        };
+
+        Ok(Array { dimensions, values })
+    }
+
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        match &self.values {
+            ArrayValues::Numbers(numbers) => numbers.len(),
+            ArrayValues::Chars(chars) => chars.len(),
+        }
+    }
+
+    /// Whether the array has no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The elements split into rows along the last dimension, as text.
+    /// Numeric rows hold one field per element, character rows one string.
+    pub fn rows(&self) -> Vec<Vec<String>> {
+        let width = self.dimensions.last().copied().unwrap_or(1).max(1) as usize;
+        match &self.values {
+            ArrayValues::Numbers(numbers) => numbers
+                .chunks(width)
+                .map(|row| row.iter().map(|&n| format_number(n)).collect())
+                .collect(),
+            ArrayValues::Chars(chars) => {
+                chars.chunks(width).map(|row| vec![to_text(row)]).collect()
+            }
+        }
+    }
+
+    /// The array as CSV, one line per row along the last dimension
+    pub fn to_csv(&self) -> String {
+        self.rows()
+            .iter()
+            .map(|row| {
+                let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+                fields.join(",") + "\n"
+            })
+            .collect()
+    }
+}
+
+// Quotes a CSV field when it contains a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dimensions: Vec<String> = self.dimensions.iter().map(u16::to_string).collect();
+        write!(f, "({}) =", dimensions.join(","))?;
+
+        match &self.values {
+            ArrayValues::Numbers(numbers) => {
+                let numbers: Vec<String> = numbers.iter().map(|&n| format_number(n)).collect();
+                write!(f, " {}", numbers.join(", "))
+            }
+            // Character arrays read best as strings along the last dimension
+            ArrayValues::Chars(chars) => {
+                let width = self.dimensions.last().copied().unwrap_or(1).max(1) as usize;
+                let rows: Vec<String> = chars
+                    .chunks(width)
+                    .map(|row| format!("\"{}\"", to_text(row)))
+                    .collect();
+                write!(f, " {}", rows.join(", "))
+            }
+        }
+    }
+}
+
+/// An entry of the variables area that follows the program
+#[derive(Debug, Clone)]
+pub enum Variable {
+    /// A numeric variable, whose name may be several letters long
+    Number {
+        /// The full name, as stored
+        name: String,
+        /// Value of the variable
+        value: f64,
+    },
+    /// A string variable such as `a$`
+    String {
+        /// Letter of the name
+        name: char,
+        /// Characters of the string
+        value: Vec<u8>,
+    },
+    /// A numeric array such as `a()`
+    NumArray {
+        /// Letter of the name
+        name: char,
+        /// Dimensions and elements
+        array: Array,
+    },
+    /// A character array such as `a$()`
+    CharArray {
+        /// Letter of the name
+        name: char,
+        /// Dimensions and elements
+        array: Array,
+    },
+    /// Control variable of a `FOR` loop
+    ForLoop {
+        /// Letter of the name
+        name: char,
+        /// Current value
+        value: f64,
+        /// Value the loop ends at
+        limit: f64,
+        /// Value added on each `NEXT`
+        step: f64,
+        /// Line the loop continues at
+        line: u16,
+        /// Statement within that line
+        statement: u8,
+    },
+}
+
+/// Marks the end of the variables area
+const END_OF_VARIABLES: u8 = 0x80;
+
+/// Names are stored lower case in the bottom 5 bits of the first byte
+pub fn letter(byte: u8) -> char {
+    ((byte & 0x1F) | 0x60) as char
+}
+
+impl Variable {
+    /// Reads one variable, or returns `None` at the end of the area
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut first = [0; 1];
+        if reader.read(&mut first)? == 0 || first[0] == END_OF_VARIABLES {
+            return Ok(None);
+        }
+        let first = first[0];
+        let name = letter(first);
+
+        let variable = match first >> 5 {
+            0b011 => Variable::Number {
+                name: name.to_string(),
+                value: read_number(reader)?,
+            },
+            0b101 => {
+                let mut long_name = name.to_string();
+                loop {
+                    let byte = reader.read_u8()?;
+                    long_name.push((byte & 0x7F) as char);
+                    if byte & 0x80 != 0 {
+                        break;
+                    }
+                }
+                Variable::Number {
+                    name: long_name,
+                    value: read_number(reader)?,
+                }
+            }
+            0b010 => {
+                let len = reader.read_u16::<LittleEndian>()? as usize;
+                let mut value = vec![0; len];
+                reader.read_exact(&mut value)?;
+                Variable::String { name, value }
+            }
+            0b100 | 0b110 => {
+                let len = reader.read_u16::<LittleEndian>()? as usize;
+                let mut contents = vec![0; len];
+                reader.read_exact(&mut contents)?;
+                let array = Array::from_bytes(&mut contents.as_slice(), first >> 5 == 0b100)?;
+                if first >> 5 == 0b100 {
+                    Variable::NumArray { name, array }
+                } else {
+                    Variable::CharArray { name, array }
+                }
+            }
+            0b111 => Variable::ForLoop {
+                name,
+                value: read_number(reader)?,
+                limit: read_number(reader)?,
+                step: read_number(reader)?,
+                line: reader.read_u16::<LittleEndian>()?,
+                statement: reader.read_u8()?,
+            },
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid variable type {:#04x}", first),
+                ))
+            }
+        };
+
+        Ok(Some(variable))
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variable::Number { name, value } => write!(f, "{} = {}", name, format_number(*value)),
+            Variable::String { name, value } => write!(f, "{}$ = \"{}\"", name, to_text(value)),
+            Variable::NumArray { name, array } => write!(f, "{}{}", name, array),
+            Variable::CharArray { name, array } => write!(f, "{}${}", name, array),
+            Variable::ForLoop {
+                name,
+                value,
+                limit,
+                step,
+                line,
+                statement,
+            } => write!(
+                f,
+                "FOR {} = {} TO {} STEP {}, looping to line {}:{}",
+                name,
+                format_number(*value),
+                format_number(*limit),
+                format_number(*step),
+                line,
+                statement
+            ),
+        }
+    }
+}
+
+/// A BASIC program file: the program lines followed by the variables that
+/// were saved with them
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// Lines of the program in order
+    pub lines: Vec<Line>,
+    /// Variables saved with the program
+    pub variables: Vec<Variable>,
+}
+
+impl Program {
+    /// Splits the data block of a program file at `len_program`, taken from
+    /// its header, into lines and variables
+    pub fn from_bytes(data: &[u8], len_program: u16) -> io::Result<Self> {
+        let (program, variables) = data.split_at((len_program as usize).min(data.len()));
+
+        let mut lines = Vec::new();
+        let mut reader = program;
+        while !reader.is_empty() {
+            lines.push(Line::from_bytes(&mut reader)?);
+        }
+
+        let mut reader = variables;
+        let variables = std::iter::from_fn(|| Variable::from_bytes(&mut reader).transpose())
+            .collect::<io::Result<_>>()?;
+
+        Ok(Program { lines, variables })
+    }
+
+    /// Parses a listing with one numbered line per line of text, in the
+    /// format the lines are displayed in. Lines are sorted by number and a
+    /// repeated number replaces the earlier line, as when typing them in.
+    pub fn from_source(source: &str) -> io::Result<Self> {
+        let mut lines: Vec<Line> = Vec::new();
+
+        for (index, text) in source.lines().enumerate() {
+            let text = text.trim_start();
+            if text.trim().is_empty() {
+                continue;
+            }
+
+            let digits = text.chars().take_while(char::is_ascii_digit).count();
+            let number = text[..digits]
+                .parse::<u16>()
+                .ok()
+                .filter(|number| (1..=9999).contains(number))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "Line {}: expected a line number from 1 to 9999",
+                        index + 1
+                    ))
+                })?;
+            let text = &text[digits..];
+            let text = text.strip_prefix(' ').unwrap_or(text);
+
+            let line = Line {
+                number,
+                text: text.trim_end().to_string(),
+            };
+            match lines.binary_search_by_key(&number, |line| line.number) {
+                Ok(position) => lines[position] = line,
+                Err(position) => lines.insert(position, line),
+            }
+        }
+
+        Ok(Program {
+            lines,
+            variables: Vec::new(),
+        })
+    }
+
+    /// The tokenized program lines. Variables are not written, so this is
+    /// the data of a program saved after `CLEAR`.
+    pub fn lines_to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for line in &self.lines {
+            bytes.extend(
+                line.to_bytes()
+                    .map_err(|error| invalid(format!("Line {}: {}", line.number, error)))?,
+            );
+        }
+        Ok(bytes)
+    }
+
+    /// Header and data block of the program saved as a file, optionally
+    /// starting itself at `autostart_line`
+    pub fn to_blocks(&self, filename: &str, autostart_line: Option<u16>) -> io::Result<[Block; 2]> {
+        let data = self.lines_to_bytes()?;
+        let len_data = u16::try_from(data.len())
+            .map_err(|_| invalid("Program too long for one block".to_string()))?;
+
+        let header = Header {
+            header_type: HeaderTypeEnum::Program,
+            filename: tap::filename_bytes(filename),
+            len_data,
+            params: BlockParams::Program(ProgramParams {
+                autostart_line: autostart_line.unwrap_or(NO_AUTOSTART),
+                len_program: len_data,
+            }),
+        };
+
+        Ok([
+            Block::from_header(&header),
+            Block::new(FlagEnum::Data, data),
+        ])
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        for variable in &self.variables {
+            writeln!(f, "{}", variable)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_lists_tokens_hidden_numbers_and_variables() {
+        let mut data = vec![0, 10, 9, 0, 0xF5, b'1', NUMBER_MARKER, 0, 0, 2, 0, 0, 0x0D];
+        data.extend([0x61, 0, 0, 5, 0, 0, END_OF_VARIABLES]);
+        let program = Program::from_bytes(&data, 13).unwrap();
+        assert_eq!(program.to_string(), "  10 PRINT 1{=2}\na = 5\n");
+    }
+
+    #[test]
+    fn numbers_match_the_rom_encoding() {
+        assert_eq!(encode_number(0.1).unwrap(), [0x7D, 0x4C, 0xCC, 0xCC, 0xCD]);
+        assert_eq!(encode_number(1e10).unwrap(), [0xA2, 0x15, 0x02, 0xF9, 0x00]);
+        assert_eq!(decode_number(&[0x7D, 0x4C, 0xCC, 0xCC, 0xCD]) as f32, 0.1);
+        assert_eq!(decode_number(&[0xA2, 0x15, 0x02, 0xF9, 0x00]), 1e10);
+    }
+
+    #[test]
+    fn whole_numbers_use_the_small_integer_form() {
+        assert_eq!(encode_number(10.0).unwrap(), [0, 0, 10, 0, 0]);
+        assert_eq!(encode_number(-1.0).unwrap(), [0, 0xFF, 0xFF, 0xFF, 0]);
+        assert_eq!(decode_number(&[0, 0xFF, 0xFF, 0xFF, 0]), -1.0);
+        assert_eq!(decode_number(&[0, 0, 0xFF, 0xFF, 0]), 65535.0);
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for value in [0.5, -0.25, 2.71, 123456.7, -1e-3, 1e30] {
+            let decoded = decode_number(&encode_number(value).unwrap());
+            assert!((decoded - value).abs() <= value.abs() * 1e-9, "{}", value);
+        }
+        assert!(encode_number(1e39).is_err());
+    }
+
+    #[test]
+    fn program_round_trips_through_a_file() {
+        let source = "  10 REM hello\n  20 PRINT \"A\";1.5\n  30 GO TO 10\n";
+        let program = Program::from_source(source).unwrap();
+        let [header, data] = program.to_blocks("test", Some(10)).unwrap();
+
+        let BlockParams::Program(params) = header.header().unwrap().params else {
+            panic!("not a program header");
+        };
+        assert_eq!(params.autostart_line, 10);
+        let loaded = Program::from_bytes(&data.payload, params.len_program).unwrap();
+        assert_eq!(loaded.to_string(), source);
+        assert_eq!(loaded.lines_to_bytes().unwrap(), data.payload);
+    }
+
+    #[test]
+    fn oversized_arrays_are_rejected() {
+        let mut data: &[u8] = &[
+            5, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        assert!(Array::from_bytes(&mut data, true).is_err());
+        let mut data: &[u8] = &[1, 3, 0, b'a', b'b'];
+        assert!(Array::from_bytes(&mut data, false).is_err());
+        let mut data: &[u8] = &[1, 2, 0, b'a', b'b'];
+        assert_eq!(Array::from_bytes(&mut data, false).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,375 @@
+//! CSW (Compressed Square Wave) files: the tape signal as run lengths in
+//! samples, stored as RLE (version 1) or zlib compressed RLE (version 2)
+
+use crate::decode::{self, DecodedBlock};
+use crate::pulse::{self, Pulse, PulseOptions, CPU_CLOCK};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Bytes every CSW file starts with
+pub const SIGNATURE: &[u8; 23] = b"Compressed Square Wave\x1A";
+
+/// Plain run-length encoding, one byte per pulse
+pub const RLE: u8 = 1;
+/// The RLE stream compressed with zlib, only in version 2 files
+pub const Z_RLE: u8 = 2;
+
+/// Written into the header of version 2 files
+const ENCODING_APPLICATION: &[u8] = b"zxtape";
+
+/// A CSW recording: the lengths of the pulses at a fixed sample rate
+#[derive(Debug, Clone)]
+pub struct Csw {
+    /// Samples per second
+    pub sample_rate: u32,
+    /// Level of the signal during the first pulse
+    pub initial_level: bool,
+    /// Pulse lengths in samples
+    pub runs: Vec<u32>,
+}
+
+impl Csw {
+    /// Reads a CSW file of version 1 or 2
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut signature = [0; 23];
+        reader.read_exact(&mut signature)?;
+        if &signature != SIGNATURE {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a CSW file"));
+        }
+
+        let major = reader.read_u8()?;
+        let _minor = reader.read_u8()?;
+        let (sample_rate, compression, flags) = match major {
+            1 => {
+                let sample_rate = reader.read_u16::<LittleEndian>()? as u32;
+                let compression = reader.read_u8()?;
+                let flags = reader.read_u8()?;
+                reader.read_exact(&mut [0; 3])?;
+                (sample_rate, compression, flags)
+            }
+            2 => {
+                let sample_rate = reader.read_u32::<LittleEndian>()?;
+                let _pulse_count = reader.read_u32::<LittleEndian>()?;
+                let compression = reader.read_u8()?;
+                let flags = reader.read_u8()?;
+                let extension_len = reader.read_u8()?;
+                // Encoding application, then the header extension
+                reader.read_exact(&mut vec![0; 16 + extension_len as usize])?;
+                (sample_rate, compression, flags)
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unsupported CSW version {}", major),
+                ))
+            }
+        };
+
+        if sample_rate == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid CSW sample rate",
+            ));
+        }
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(Csw {
+            sample_rate,
+            initial_level: flags & 0x01 != 0,
+            runs: decode_runs(compression, &data)?,
+        })
+    }
+
+    /// Opens and reads a CSW file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Csw::from_bytes(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Samples the pulses at `sample_rate`, the same way a WAV file of them
+    /// would be rendered, and stores the length of every stretch of one level
+    pub fn from_pulses(pulses: &[Pulse], sample_rate: u32) -> Self {
+        let mut runs = Vec::new();
+        let mut initial_level = false;
+        let mut run_level = None;
+        let mut run_start = 0;
+        let mut end = 0;
+
+        for (level, pulse_end) in pulse::pulse_levels(pulses, sample_rate) {
+            let start = end;
+            end = pulse_end;
+            // Level changes shorter than a sample never make it into the signal
+            if end == start {
+                continue;
+            }
+
+            match run_level {
+                None => initial_level = level,
+                Some(run_level) if run_level != level => {
+                    runs.push((start - run_start).min(u32::MAX as u64) as u32);
+                    run_start = start;
+                }
+                Some(_) => {}
+            }
+            run_level = Some(level);
+        }
+
+        if run_level.is_some() {
+            runs.push((end - run_start).min(u32::MAX as u64) as u32);
+        }
+
+        Csw {
+            sample_rate,
+            initial_level,
+            runs,
+        }
+    }
+
+    /// Writes a version 1.01 file with RLE data, or a version 2.00 file with
+    /// Z-RLE data
+    pub fn write_to<W: Write>(&self, writer: &mut W, version: u8) -> io::Result<()> {
+        let flags = self.initial_level as u8;
+        writer.write_all(SIGNATURE)?;
+        match version {
+            1 => {
+                let sample_rate = u16::try_from(self.sample_rate).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "CSW version 1 only supports sample rates up to 65535 Hz",
+                    )
+                })?;
+                writer.write_all(&[1, 1])?;
+                writer.write_u16::<LittleEndian>(sample_rate)?;
+                writer.write_all(&[RLE, flags, 0, 0, 0])?;
+                writer.write_all(&encode_runs(RLE, &self.runs)?)
+            }
+            2 => {
+                let mut application = [0; 16];
+                application[..ENCODING_APPLICATION.len()].copy_from_slice(ENCODING_APPLICATION);
+                writer.write_all(&[2, 0])?;
+                writer.write_u32::<LittleEndian>(self.sample_rate)?;
+                writer.write_u32::<LittleEndian>(self.runs.len() as u32)?;
+                writer.write_all(&[Z_RLE, flags, 0])?;
+                writer.write_all(&application)?;
+                writer.write_all(&encode_runs(Z_RLE, &self.runs)?)
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only CSW versions 1 and 2 can be written",
+            )),
+        }
+    }
+
+    /// Writes a CSW file of the given version, 1 or 2
+    pub fn save<P: AsRef<Path>>(&self, path: P, version: u8) -> io::Result<()> {
+        // Encode first so an unsupported version doesn't leave a broken file
+        let mut data = Vec::new();
+        self.write_to(&mut data, version)?;
+        fs::write(path, data)
+    }
+
+    /// One line summary of the recording
+    pub fn describe(&self) -> String {
+        let samples: u64 = self.runs.iter().map(|&run| run as u64).sum();
+        format!(
+            "CSW recording, {} pulses at {} Hz, {:.1} s",
+            self.runs.len(),
+            self.sample_rate,
+            samples as f64 / self.sample_rate as f64
+        )
+    }
+
+    /// The recording as pulses, starting at the initial level
+    pub fn pulses(&self, options: &PulseOptions) -> Vec<Pulse> {
+        // Every run starts with an edge, so start from the opposite level
+        let mut pulses = vec![Pulse::Level(!self.initial_level, 0)];
+        runs_to_pulses(&self.runs, self.sample_rate, options, &mut pulses);
+        pulses
+    }
+
+    /// Pulse lengths in T-states with the sample each pulse starts at, as
+    /// [`decode::half_waves`] finds them in a WAV recording
+    pub fn half_waves(&self) -> Vec<(u32, usize)> {
+        let mut half_waves = Vec::with_capacity(self.runs.len());
+        let mut start = 0;
+        for &run in &self.runs {
+            let tstates = run as u64 * CPU_CLOCK as u64 / self.sample_rate as u64;
+            half_waves.push((tstates.min(u32::MAX as u64) as u32, start));
+            start += run as usize;
+        }
+        half_waves
+    }
+
+    /// Reads the blocks saved in the recording
+    pub fn decode(&self) -> Vec<DecodedBlock> {
+        decode::decode_half_waves(&self.half_waves(), self.sample_rate)
+    }
+}
+
+/// Expands CSW data into pulse lengths in samples. Compression 1 is plain RLE,
+/// 2 is the same RLE stream compressed with zlib.
+pub fn decode_runs(compression: u8, data: &[u8]) -> io::Result<Vec<u32>> {
+    let rle = match compression {
+        RLE => data.to_vec(),
+        Z_RLE => {
+            let mut rle = Vec::new();
+            ZlibDecoder::new(data).read_to_end(&mut rle)?;
+            rle
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unknown CSW compression type",
+            ))
+        }
+    };
+
+    let mut runs = Vec::new();
+    let mut reader = rle.as_slice();
+    while let Ok(length) = reader.read_u8() {
+        // A zero byte is followed by the real length as a 32 bit value
+        runs.push(if length == 0 {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            length as u32
+        });
+    }
+
+    Ok(runs)
+}
+
+/// The opposite of [`decode_runs`]
+pub fn encode_runs(compression: u8, runs: &[u32]) -> io::Result<Vec<u8>> {
+    let mut rle = Vec::with_capacity(runs.len());
+    for &run in runs {
+        if (1..=255).contains(&run) {
+            rle.push(run as u8);
+        } else {
+            rle.push(0);
+            rle.write_u32::<LittleEndian>(run)?;
+        }
+    }
+
+    match compression {
+        RLE => Ok(rle),
+        Z_RLE => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&rle)?;
+            encoder.finish()
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Unknown CSW compression type",
+        )),
+    }
+}
+
+/// Converts sample counts to T-states, working from the total number of
+/// samples so far. Rounding up makes each edge land on the same sample again
+/// when the pulses are rendered at `sample_rate`.
+pub(crate) fn runs_to_pulses(
+    runs: &[u32],
+    sample_rate: u32,
+    options: &PulseOptions,
+    pulses: &mut Vec<Pulse>,
+) {
+    if sample_rate == 0 {
+        return;
+    }
+
+    let mut samples: u64 = 0;
+    let mut tstates: u64 = 0;
+    for &run in runs {
+        samples += run as u64;
+        let end = (samples * CPU_CLOCK as u64).div_ceil(sample_rate as u64);
+        pulses.push(Pulse::Edge(options.duration((end - tstates) as u32)));
+        tstates = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pulse::SampleOptions;
+    use crate::tap::tests::code_tape;
+
+    fn tape_pulses() -> Vec<Pulse> {
+        code_tape(vec![0, 1, 2, 0xFF, 0x80]).pulses(&PulseOptions::default())
+    }
+
+    fn write_and_read(csw: &Csw, version: u8) -> Csw {
+        let mut data = Vec::new();
+        csw.write_to(&mut data, version).unwrap();
+        assert!(data.starts_with(SIGNATURE));
+        assert_eq!(data[23], version);
+        Csw::from_bytes(&mut data.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn versions_1_and_2_round_trip() {
+        let csw = Csw::from_pulses(&tape_pulses(), 44100);
+        for version in [1, 2] {
+            let read = write_and_read(&csw, version);
+            assert_eq!(read.sample_rate, csw.sample_rate);
+            assert_eq!(read.initial_level, csw.initial_level);
+            assert_eq!(read.runs, csw.runs);
+        }
+    }
+
+    #[test]
+    fn long_runs_use_the_32_bit_form() {
+        let csw = Csw {
+            sample_rate: 22050,
+            initial_level: true,
+            runs: vec![1, 255, 256, 100_000],
+        };
+        assert_eq!(
+            encode_runs(RLE, &csw.runs).unwrap(),
+            [1, 255, 0, 0, 1, 0, 0, 0, 0xA0, 0x86, 0x01, 0x00]
+        );
+        for version in [1, 2] {
+            assert_eq!(write_and_read(&csw, version).runs, csw.runs);
+        }
+    }
+
+    #[test]
+    fn pulses_render_the_same_samples_as_the_tape() {
+        let pulses = tape_pulses();
+        for sample_rate in [22050, 44100, 48000] {
+            let options = SampleOptions {
+                sample_rate,
+                ..SampleOptions::default()
+            };
+            let csw = Csw::from_pulses(&pulses, sample_rate);
+            assert_eq!(
+                pulse::pulses_to_samples(&csw.pulses(&PulseOptions::default()), &options),
+                pulse::pulses_to_samples(&pulses, &options)
+            );
+        }
+    }
+
+    #[test]
+    fn recording_decodes_to_the_tape_blocks() {
+        let blocks: Vec<Vec<u8>> = Csw::from_pulses(&tape_pulses(), 44100)
+            .decode()
+            .into_iter()
+            .map(|block| block.data)
+            .collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1], [0xFF, 0, 1, 2, 0xFF, 0x80, 0x83]);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let csw = Csw::from_pulses(&tape_pulses(), 44100);
+        assert!(csw.write_to(&mut Vec::new(), 3).is_err());
+        let mut data = SIGNATURE.to_vec();
+        data.extend([3, 0]);
+        assert!(Csw::from_bytes(&mut data.as_slice()).is_err());
+    }
+}
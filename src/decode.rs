@@ -0,0 +1,285 @@
+//! Recovering tape blocks from audio recordings
+
+use crate::pulse::{
+    CPU_CLOCK, MIN_PILOT_PULSES, ONE_PULSE, PILOT_PULSE, SYNC1_PULSE, SYNC2_PULSE, ZERO_PULSE,
+};
+use crate::tap::{Block, ChecksumStatus};
+use crate::wav::to_io_error;
+use hound::{SampleFormat, WavReader};
+use std::io::{self, Error, ErrorKind, Read};
+
+/// Pulses more than this far from the expected length are rejected
+const TOLERANCE: f64 = 0.35;
+
+/// Schmitt trigger thresholds, as a fraction of the peak amplitude
+const HYSTERESIS: f32 = 0.05;
+
+/// How well a block could be read from the recording
+#[derive(Debug, Clone)]
+pub struct SignalQuality {
+    /// Number of pilot pulses before the sync
+    pub pilot_pulses: usize,
+    /// Measured pilot length relative to the ROM timing, > 1.0 is a slow tape
+    pub speed: f64,
+    /// Mean deviation of the bit periods from their expected length, in percent
+    pub jitter: f64,
+    /// Bits whose two half-waves disagreed on the bit value
+    pub ambiguous_bits: usize,
+    /// Bits left over after the last whole byte
+    pub trailing_bits: usize,
+}
+
+/// A block found in a recording
+#[derive(Debug, Clone)]
+pub struct DecodedBlock {
+    /// Flag, payload and checksum, as they appear in a TAP file
+    pub data: Vec<u8>,
+    /// Position of the pilot tone in the recording, in seconds
+    pub position: f64,
+    /// Whether the checksum byte matches the flag and payload
+    pub checksum_ok: bool,
+    /// How cleanly the block was read
+    pub quality: SignalQuality,
+}
+
+/// Reads one channel of a WAV file as samples between -1.0 and 1.0
+pub fn read_wav<R: Read>(reader: R, channel: u16) -> io::Result<(Vec<f32>, u32)> {
+    let reader = WavReader::new(reader).map_err(to_io_error)?;
+    let spec = reader.spec();
+    if channel >= spec.channels {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("The recording only has {} channel(s)", spec.channels),
+        ));
+    }
+
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(to_io_error)?,
+        SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<_, _>>()
+                .map_err(to_io_error)?
+        }
+    };
+
+    let samples = samples
+        .into_iter()
+        .skip(channel as usize)
+        .step_by(spec.channels as usize)
+        .collect();
+
+    Ok((samples, spec.sample_rate))
+}
+
+/// Finds the level changes of the recording and returns the lengths of the
+/// half-waves between them in T-states, together with the sample index each
+/// half-wave starts at.
+pub fn half_waves(samples: &[f32], sample_rate: u32) -> Vec<(u32, usize)> {
+    let mean = samples.iter().sum::<f32>() / samples.len().max(1) as f32;
+    let peak = samples
+        .iter()
+        .fold(0.0f32, |peak, &sample| peak.max((sample - mean).abs()));
+    let threshold = peak * HYSTERESIS;
+
+    let mut half_waves = Vec::new();
+    let mut level = false;
+    let mut last_edge = 0;
+
+    for (index, &sample) in samples.iter().enumerate() {
+        let sample = sample - mean;
+        let new_level = if sample > threshold {
+            true
+        } else if sample < -threshold {
+            false
+        } else {
+            level
+        };
+
+        if new_level != level {
+            let samples = (index - last_edge) as u64;
+            let tstates = samples * CPU_CLOCK as u64 / sample_rate as u64;
+            half_waves.push((tstates.min(u32::MAX as u64) as u32, last_edge));
+            level = new_level;
+            last_edge = index;
+        }
+    }
+
+    half_waves
+}
+
+fn within(length: u32, expected: f64) -> bool {
+    (length as f64 - expected).abs() <= expected * TOLERANCE
+}
+
+/// Locks onto pilot tones and reads the bytes following each sync pulse
+pub fn decode_half_waves(half_waves: &[(u32, usize)], sample_rate: u32) -> Vec<DecodedBlock> {
+    let mut blocks = Vec::new();
+    let mut index = 0;
+
+    while index < half_waves.len() {
+        // Pilot: a long run of half-waves of about the same length. Its length
+        // sets the speed of the sync and data after it, so tapes saved faster
+        // or slower than the ROM timings are read as well.
+        let start = index;
+        let mut pilot_total: u64 = 0;
+        while let Some(&(length, _)) = half_waves.get(index) {
+            let count = (index - start) as f64;
+            if count > 0.0 && !within(length, pilot_total as f64 / count) {
+                break;
+            }
+            pilot_total += length as u64;
+            index += 1;
+        }
+        let pilot_pulses = index - start;
+        if pilot_pulses < MIN_PILOT_PULSES {
+            index = start + 1;
+            continue;
+        }
+
+        let speed = pilot_total as f64 / pilot_pulses as f64 / PILOT_PULSE as f64;
+
+        // Sync: two short half-waves, checked as a whole since recordings
+        // rarely keep the split between them
+        let sync = SYNC1_PULSE as f64 + SYNC2_PULSE as f64;
+        match half_waves.get(index..index + 2) {
+            Some([(first, _), (second, _)])
+                if within(first.saturating_add(*second), sync * speed)
+                    && (*first as f64) < PILOT_PULSE as f64 * speed * (1.0 - TOLERANCE) =>
+            {
+                index += 2
+            }
+            _ => continue,
+        }
+
+        // Data: pairs of half-waves, short for 0 and long for 1
+        let zero = 2.0 * ZERO_PULSE as f64 * speed;
+        let one = 2.0 * ONE_PULSE as f64 * speed;
+        let threshold = (zero + one) / 2.0;
+        // Blocks saved without a pause run straight into the next pilot, so
+        // stop halfway between a 1 bit and a pilot wave
+        let longest = (one + 2.0 * PILOT_PULSE as f64 * speed) / 2.0;
+
+        let mut bits = Vec::new();
+        let mut deviation = 0.0;
+        let mut ambiguous_bits = 0;
+        while let Some(&[(first, _), (second, _)]) = half_waves.get(index..index + 2) {
+            let period = first as f64 + second as f64;
+            if period < zero * (1.0 - TOLERANCE) || period > longest {
+                break;
+            }
+
+            let bit = period > threshold;
+            let expected = if bit { one } else { zero };
+            deviation += (period - expected).abs() / expected;
+            if (first as f64 > threshold / 2.0) != (second as f64 > threshold / 2.0) {
+                ambiguous_bits += 1;
+            }
+
+            bits.push(bit);
+            index += 2;
+        }
+
+        let data: Vec<u8> = bits
+            .chunks_exact(8)
+            .map(|byte| byte.iter().fold(0, |value, &bit| value << 1 | bit as u8))
+            .collect();
+        if data.is_empty() {
+            continue;
+        }
+
+        let checksum_ok = ChecksumStatus::of(&data).is_ok();
+        blocks.push(DecodedBlock {
+            position: half_waves[start].1 as f64 / sample_rate as f64,
+            checksum_ok,
+            quality: SignalQuality {
+                pilot_pulses,
+                speed,
+                jitter: deviation / bits.len() as f64 * 100.0,
+                ambiguous_bits,
+                trailing_bits: bits.len() % 8,
+            },
+            data,
+        });
+    }
+
+    blocks
+}
+
+impl DecodedBlock {
+    /// The recovered data as a TAP block
+    pub fn block(&self) -> Block {
+        Block::from_tape_data(&self.data)
+    }
+}
+
+/// Decodes every block found in one channel of a WAV file
+pub fn decode_wav<R: Read>(reader: R, channel: u16) -> io::Result<Vec<DecodedBlock>> {
+    let (samples, sample_rate) = read_wav(reader, channel)?;
+    Ok(decode_half_waves(
+        &half_waves(&samples, sample_rate),
+        sample_rate,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pulse::{self, PulseOptions, SampleOptions};
+    use crate::tap::tests::code_tape;
+    use crate::tap::Tape;
+
+    fn short_tape() -> Tape {
+        code_tape((0..=255).collect())
+    }
+
+    fn decode_rendered(tape: &Tape, options: &PulseOptions, sample_rate: u32) -> Vec<Vec<u8>> {
+        let samples = pulse::pulses_to_samples(
+            &tape.pulses(options),
+            &SampleOptions {
+                sample_rate,
+                ..SampleOptions::default()
+            },
+        );
+        decode_half_waves(&half_waves(&samples, sample_rate), sample_rate)
+            .into_iter()
+            .map(|block| {
+                assert!(block.checksum_ok);
+                block.data
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rendered_tape_decodes_to_the_same_blocks() {
+        let tape = short_tape();
+        for sample_rate in [22050, 44100, 48000] {
+            assert_eq!(
+                decode_rendered(&tape, &PulseOptions::default(), sample_rate),
+                tape.tape_blocks()
+            );
+        }
+    }
+
+    #[test]
+    fn turbo_tape_decodes_to_the_same_blocks() {
+        let tape = short_tape();
+        for speed in [1.5, 2.0, 3.0] {
+            let options = PulseOptions {
+                speed,
+                ..PulseOptions::default()
+            };
+            assert_eq!(decode_rendered(&tape, &options, 44100), tape.tape_blocks());
+        }
+    }
+
+    #[test]
+    fn silence_decodes_to_nothing() {
+        assert!(decode_half_waves(&half_waves(&[0.0; 1000], 44100), 44100).is_empty());
+    }
+}
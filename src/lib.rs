@@ -0,0 +1,149 @@
+//! Reading, writing and rendering of ZX Spectrum tape images.
+//!
+//! TAP files are parsed into [`Tape`]s of [`Block`]s and TZX files into
+//! [`Tzx`] block lists. Both render to the same [`Pulse`] stream, which can
+//! be played, written to WAV or CSW, or decoded back from a recording.
+//!
+//! Playback lives in the `player` module, behind the default `playback`
+//! feature. It pulls in rodio and with it ALSA on Linux, so crates that only
+//! read and convert tapes can turn it off with `default-features = false`.
+
+#![warn(missing_docs)]
+
+pub mod basic;
+pub mod csw;
+pub mod decode;
+#[cfg(feature = "playback")]
+pub mod player;
+mod png;
+pub mod pulse;
+pub mod screen;
+pub mod tap;
+pub mod tzx;
+pub mod wav;
+
+pub use basic::Program;
+pub use csw::Csw;
+pub use pulse::{Pulse, PulseOptions};
+pub use screen::Screen;
+pub use tap::{
+    ArrayParams, Block, BlockParams, BytesParams, ChecksumStatus, FlagEnum, Header, HeaderTypeEnum,
+    ProgramParams, TapChunks, Tape, TapeFile,
+};
+pub use tzx::Tzx;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// A tape image in any of the supported formats
+#[derive(Debug)]
+pub enum TapeImage {
+    /// A TAP file
+    Tap(Tape),
+    /// A TZX file
+    Tzx(Tzx),
+    /// A CSW recording
+    Csw(Csw),
+}
+
+impl TapeImage {
+    /// Opens a TAP, TZX or CSW file, telling them apart by the TZX and CSW
+    /// signatures
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let start = reader.fill_buf()?;
+        if start.starts_with(tzx::SIGNATURE) {
+            Ok(TapeImage::Tzx(Tzx::from_bytes(&mut reader)?))
+        } else if start.starts_with(csw::SIGNATURE) {
+            Ok(TapeImage::Csw(Csw::from_bytes(&mut reader)?))
+        } else {
+            Ok(TapeImage::Tap(Tape::from_bytes(&mut reader)?))
+        }
+    }
+
+    /// Parses an in-memory TAP, TZX or CSW image
+    pub fn from_slice(data: &[u8]) -> io::Result<Self> {
+        if data.starts_with(tzx::SIGNATURE) {
+            Ok(TapeImage::Tzx(Tzx::from_bytes(&mut &data[..])?))
+        } else if data.starts_with(csw::SIGNATURE) {
+            Ok(TapeImage::Csw(Csw::from_bytes(&mut &data[..])?))
+        } else {
+            Ok(TapeImage::Tap(Tape::from_slice(data)?))
+        }
+    }
+
+    /// Blocks stored in the ROM format (flag, payload and checksum); for TZX
+    /// files only the standard, turbo and pure data blocks, for CSW files the
+    /// blocks the decoder finds in the recording
+    pub fn tape_blocks(&self) -> Vec<Vec<u8>> {
+        match self {
+            TapeImage::Tap(tape) => tape.tape_blocks(),
+            TapeImage::Tzx(tzx) => tzx.tap_blocks(),
+            TapeImage::Csw(csw) => csw.decode().into_iter().map(|block| block.data).collect(),
+        }
+    }
+
+    /// The blocks stored in the ROM format as a TAP tape, to get at the files
+    /// on it
+    pub fn to_tape(&self) -> Tape {
+        let blocks = self
+            .tape_blocks()
+            .iter()
+            .map(|data| Block::from_tape_data(data))
+            .collect();
+        Tape { blocks }
+    }
+
+    /// Indices of the blocks in the order they are played. For TAP files
+    /// these are the blocks of [`TapeImage::to_tape`], for TZX files the
+    /// entries of [`Tzx::blocks`]. A CSW recording is played as one block.
+    pub fn playback_order(&self) -> Vec<usize> {
+        match self {
+            TapeImage::Tap(tape) => (0..tape.blocks.len()).collect(),
+            TapeImage::Tzx(tzx) => tzx.playback_order(),
+            TapeImage::Csw(_) => vec![0],
+        }
+    }
+
+    /// Whether playback stops after the block at `index` until it is resumed,
+    /// see [`tzx::TzxBlock::stops_tape`]
+    pub fn stops_tape(&self, index: usize) -> bool {
+        match self {
+            TapeImage::Tzx(tzx) => tzx
+                .blocks
+                .get(index)
+                .is_some_and(|block| block.stops_tape()),
+            TapeImage::Tap(_) | TapeImage::Csw(_) => false,
+        }
+    }
+
+    /// Pulses of a single block, so playback can render the tape one block
+    /// at a time
+    pub fn block_pulses(&self, index: usize, options: &PulseOptions) -> io::Result<Vec<Pulse>> {
+        let mut pulses = Vec::new();
+        match self {
+            TapeImage::Tap(tape) => pulses = tape.block_pulses(index, options),
+            TapeImage::Tzx(tzx) => {
+                if let Some(block) = tzx.blocks.get(index) {
+                    block.append_pulses(&mut pulses, options)?;
+                }
+            }
+            TapeImage::Csw(csw) => {
+                if index == 0 {
+                    pulses = csw.pulses(options);
+                }
+            }
+        }
+        Ok(pulses)
+    }
+
+    /// Pulses of the whole tape, in playback order
+    pub fn pulses(&self, options: &PulseOptions) -> io::Result<Vec<Pulse>> {
+        match self {
+            TapeImage::Tap(tape) => Ok(tape.pulses(options)),
+            TapeImage::Tzx(tzx) => tzx.pulses(options),
+            TapeImage::Csw(csw) => Ok(csw.pulses(options)),
+        }
+    }
+}
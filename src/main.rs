@@ -1,274 +1,1091 @@
-use byteorder::{LittleEndian, ReadBytesExt};
-use rodio::{source::Source, OutputStream, Sink};
+mod json;
+
+use json::Json;
+#[cfg(feature = "playback")]
+use rodio::Sink;
+use std::collections::HashSet;
 use std::env;
-use std::fs::File;
-use std::io::BufRead;
-use std::io::{self, BufReader, Error, ErrorKind, Read};
-
-#[derive(Debug, Clone, PartialEq)]
-enum FlagEnum {
-    Header,
-    Data,
-}
-
-impl FlagEnum {
-    fn from_u8(value: u8) -> Option<Self> {
-        match value {
-            0x00 => Some(FlagEnum::Header),
-            0xFF => Some(FlagEnum::Data),
-            _ => None,
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+#[cfg(feature = "playback")]
+use std::sync::mpsc::{self, RecvTimeoutError};
+#[cfg(feature = "playback")]
+use std::sync::Arc;
+#[cfg(feature = "playback")]
+use std::thread;
+#[cfg(feature = "playback")]
+use std::time::Duration;
+use zxtape::basic::{Array, ArrayValues};
+#[cfg(feature = "playback")]
+use zxtape::player::{self, TapeSource, Transport};
+use zxtape::pulse::SampleOptions;
+use zxtape::tap::{BlockParams, Header, HeaderTypeEnum, TapeFile, NO_AUTOSTART};
+use zxtape::tzx::TzxBlock;
+use zxtape::wav;
+use zxtape::{
+    basic, csw, decode, screen, tap, Block, Csw, Program, PulseOptions, Screen, Tape, TapeImage,
+    Tzx,
+};
+
+#[cfg(feature = "playback")]
+const PLAYER_HELP: &str = "Commands: p pause/resume, n next block, b previous block, \
+r restart block, g <n> go to block n, s status, q quit";
+
+// Playback through one output stream, with a transport to follow and move
+// the position
+#[cfg(feature = "playback")]
+struct Player {
+    image: Arc<TapeImage>,
+    options: PulseOptions,
+    samples: SampleOptions,
+    sink: Sink,
+    transport: Arc<Transport>,
+    // One line description of every block, in playback order
+    labels: Vec<String>,
+}
+
+#[cfg(feature = "playback")]
+impl Player {
+    fn seek(&mut self, block: usize) {
+        // Once the tape has run out, playback starts over with a new source
+        if self.sink.empty() {
+            let source =
+                TapeSource::new(Arc::clone(&self.image), self.options.clone(), &self.samples);
+            self.transport = source.transport();
+            self.sink.append(source);
+        }
+        self.transport.seek(block);
+    }
+
+    fn print_status(&self) {
+        if self.sink.empty() {
+            println!("End of tape");
+            return;
         }
+
+        let block = self.transport.block();
+        println!(
+            "Block {}/{}, {:.1} s{}: {}",
+            block,
+            self.labels.len(),
+            self.transport.block_samples() as f64 / self.samples.sample_rate as f64,
+            if self.sink.is_paused() {
+                " (paused)"
+            } else {
+                ""
+            },
+            self.labels.get(block).map_or("", String::as_str),
+        );
     }
 }
 
-#[derive(Debug)]
-enum HeaderTypeEnum {
-    Program,
-    NumArray,
-    CharArray,
-    Bytes,
+#[cfg(feature = "playback")]
+fn block_labels(image: &TapeImage) -> Vec<String> {
+    match image {
+        TapeImage::Tap(tape) => tape
+            .blocks
+            .iter()
+            .map(|block| match block.header() {
+                Some(header) => format!("{}: \"{}\"", header.header_type.name(), header.name()),
+                None => format!("{}, {} bytes", block.name(), block.payload.len()),
+            })
+            .collect(),
+        TapeImage::Tzx(tzx) => tzx
+            .playback_order()
+            .iter()
+            .map(|&index| tzx.blocks[index].describe())
+            .collect(),
+        TapeImage::Csw(csw) => vec![csw.describe()],
+    }
 }
 
-impl HeaderTypeEnum {
-    fn from_u8(value: u8) -> Option<Self> {
-        match value {
-            0x00 => Some(HeaderTypeEnum::Program),
-            0x01 => Some(HeaderTypeEnum::NumArray),
-            0x02 => Some(HeaderTypeEnum::CharArray),
-            0x03 => Some(HeaderTypeEnum::Bytes),
-            _ => None,
+// Plays the whole tape, taking transport commands from standard input, one
+// per line. Without input it plays to the end.
+#[cfg(feature = "playback")]
+fn play_audio(
+    image: TapeImage,
+    options: PulseOptions,
+    samples: SampleOptions,
+    device: Option<&str>,
+) -> io::Result<()> {
+    let image = Arc::new(image);
+    let (_stream, stream_handle) = player::open_output(device)?;
+    let source = TapeSource::new(Arc::clone(&image), options.clone(), &samples);
+    let mut player = Player {
+        labels: block_labels(&image),
+        image,
+        options,
+        samples,
+        sink: player::new_sink(&stream_handle)?,
+        transport: source.transport(),
+    };
+    player.sink.append(source);
+
+    let (sender, commands) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    println!("{}", PLAYER_HELP);
+
+    let mut shown_block = None;
+    let mut input_open = true;
+    let mut ended = false;
+    loop {
+        if player.sink.empty() {
+            if !ended && input_open {
+                println!("End of tape, g <n> plays from block n, q quits");
+            }
+            ended = true;
+            if !input_open {
+                break;
+            }
+        } else {
+            ended = false;
+            if player.transport.is_stopped() && !player.sink.is_paused() {
+                player.sink.pause();
+                println!("The tape stopped, p continues");
+            }
+            if shown_block != Some(player.transport.block()) {
+                shown_block = Some(player.transport.block());
+                player.print_status();
+            }
+        }
+
+        let line = if input_open {
+            match commands.recv_timeout(Duration::from_millis(100)) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    input_open = false;
+                    continue;
+                }
+            }
+        } else {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+
+        let block = player.transport.block();
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("q"), _) => break,
+            (Some("p"), _) if player.sink.is_paused() => {
+                player.transport.resume();
+                player.sink.play();
+            }
+            (Some("p"), _) => player.sink.pause(),
+            (Some("n"), _) => player.seek(block + 1),
+            (Some("b"), _) => player.seek(block.saturating_sub(1)),
+            (Some("r"), _) => player.seek(block),
+            (Some("g"), Some(target)) => match target.parse() {
+                Ok(target) if target < player.labels.len() => player.seek(target),
+                _ => println!("No block {}", target),
+            },
+            (Some("s"), _) | (None, _) => {
+                player.print_status();
+                continue;
+            }
+            _ => {
+                println!("{}", PLAYER_HELP);
+                continue;
+            }
         }
+        // Show where playback is after every move
+        shown_block = None;
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct ProgramParams {
-    autostart_line: u16,
-    len_program: u16,
+// Options that are switched on by their name alone. They still take an
+// explicit `true` or `false`.
+const FLAGS: [&str; 6] = ["json", "flash", "loader", "append", "invert", "pilot-only"];
+
+// Command line split into positional arguments and `--name value` options
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
 }
 
-impl ProgramParams {
-    fn from_bytes(reader: &mut BufReader<File>) -> io::Result<Self> {
-        Ok(ProgramParams {
-            autostart_line: reader.read_u16::<LittleEndian>()?,
-            len_program: reader.read_u16::<LittleEndian>()?,
+impl Args {
+    fn parse<I: Iterator<Item = String>>(args: I) -> io::Result<Self> {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+        let mut args = args.peekable();
+
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = if FLAGS.contains(&name) {
+                    args.next_if(|value| value == "true" || value == "false")
+                        .unwrap_or("true".to_string())
+                } else {
+                    args.next().ok_or(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Missing value for --{}", name),
+                    ))?
+                };
+                options.push((name.to_string(), value));
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        Ok(Args {
+            positional,
+            options,
         })
     }
+
+    fn option<T: FromStr>(&self, name: &str, default: T) -> io::Result<T> {
+        Ok(self.optional(name)?.unwrap_or(default))
+    }
+
+    // Like `option`, for options without a default
+    fn optional<T: FromStr>(&self, name: &str) -> io::Result<Option<T>> {
+        match self.options.iter().rev().find(|(key, _)| key == name) {
+            Some((_, value)) => value.parse().map(Some).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid value for --{}: {}", name, value),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
 }
 
-#[derive(Debug)]
-struct BytesParams {
-    start_address: u16,
-    reserved: [u8; 2],
+// Signal options shared by playback and WAV export
+fn pulse_options(args: &Args) -> io::Result<PulseOptions> {
+    let defaults = PulseOptions::default();
+    let options = PulseOptions {
+        pause: args.optional("pause")?,
+        speed: args.option("speed", defaults.speed)?,
+        pilot_only: args.option("pilot-only", defaults.pilot_only)?,
+    };
+    options.validate()?;
+    Ok(options)
 }
 
-impl BytesParams {
-    fn from_bytes(reader: &mut BufReader<File>) -> io::Result<Self> {
-        let bytes_params = BytesParams {
-            start_address: reader.read_u16::<LittleEndian>()?,
-            reserved: [reader.read_u8()?, reader.read_u8()?],
-        };
-        // if !bytes_params.reserved.iter().all(|&x| x == 0) {
-        //     return Err(Error::new(ErrorKind::InvalidData, "Invalid bytes params"));
-        // }
+// Sample format options shared by playback and WAV export
+fn sample_options(args: &Args) -> io::Result<SampleOptions> {
+    let defaults = SampleOptions::default();
+    let options = SampleOptions {
+        sample_rate: args.option("rate", defaults.sample_rate)?,
+        bits_per_sample: args.option("bits", defaults.bits_per_sample)?,
+        amplitude: args.option("amplitude", defaults.amplitude)?,
+        inverted: args.option("invert", defaults.inverted)?,
+    };
+    options.validate()?;
+    Ok(options)
+}
+
+fn read_tzx(filename: &str) -> io::Result<Tzx> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    Tzx::from_bytes(&mut reader)
+}
+
+#[cfg(feature = "playback")]
+fn play(args: &Args) -> io::Result<()> {
+    let [filename] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape <file> [--pause <ms>] [--speed <factor>] [--pilot-only] \
+             [--rate <hz>] [--bits <8|16|24|32>] [--amplitude <0.0-1.0>] [--invert] \
+             [--device <name|n>]",
+        ));
+    };
 
-        Ok(bytes_params)
+    let options = pulse_options(args)?;
+    let samples = sample_options(args)?;
+    let image = TapeImage::open(filename)?;
+    match &image {
+        TapeImage::Tap(tape) => print_block_table(tape, true),
+        // Numbered in playback order, the numbers `g <n>` takes
+        image => {
+            if let TapeImage::Tzx(tzx) = image {
+                println!("TZX version {}.{:02}", tzx.major, tzx.minor);
+            }
+            for (index, label) in block_labels(image).iter().enumerate() {
+                println!("{:4}: {}", index, label);
+            }
+        }
     }
+
+    play_audio(
+        image,
+        options,
+        samples,
+        args.optional::<String>("device")?.as_deref(),
+    )
 }
 
-#[derive(Debug)]
-struct ArrayParams {
-    reserved: u8,
-    var_name: u8,
-    reserved1: [u8; 2],
+#[cfg(not(feature = "playback"))]
+fn play(_args: &Args) -> io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Playback needs zxtape built with the playback feature",
+    ))
 }
 
-impl ArrayParams {
-    fn from_bytes(reader: &mut BufReader<File>) -> io::Result<Self> {
-        let array_params = ArrayParams {
-            reserved: reader.read_u8()?,
-            var_name: reader.read_u8()?,
-            reserved1: [reader.read_u8()?, reader.read_u8()?],
+// Lists the audio outputs that `--device` can pick, by number or name
+#[cfg(feature = "playback")]
+fn devices(args: &Args) -> io::Result<()> {
+    if !args.positional.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Usage: zxtape devices"));
+    }
+
+    let devices = player::output_devices()?;
+    if devices.is_empty() {
+        println!("No audio output devices found");
+    }
+    let default = player::default_output_device();
+    for (index, name) in devices.iter().enumerate() {
+        let marker = if default.as_ref() == Some(name) {
+            " (default)"
+        } else {
+            ""
         };
-        if !array_params.reserved1.iter().all(|&x| x == 0) {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid array params"));
+        println!("{:4}: {}{}", index, name, marker);
+    }
+    Ok(())
+}
+
+// The header fields as a JSON object, with the parameters that apply to its
+// type
+fn header_json(header: &Header) -> Json {
+    let mut entries = vec![
+        ("type", Json::from(header.header_type.name())),
+        ("type_byte", Json::from(header.header_type.to_u8())),
+        ("filename", Json::from(header.name())),
+        ("length", Json::from(header.len_data)),
+    ];
+
+    match &header.params {
+        BlockParams::Program(params) => {
+            let autostart = Some(params.autostart_line).filter(|&line| line < NO_AUTOSTART);
+            entries.push(("autostart_line", Json::from(autostart)));
+            entries.push(("program_length", Json::from(params.len_program)));
         }
+        BlockParams::Array(params) => {
+            entries.push(("variable", Json::from(params.name())));
+        }
+        BlockParams::Bytes(params) => {
+            entries.push(("start_address", Json::from(params.start_address)));
+        }
+        BlockParams::Unknown(params) => {
+            entries.push(("parameters", Json::from(params.to_vec())));
+        }
+    }
+
+    Json::object(entries)
+}
+
+// Flag, length, checksum and the header if it is one, as the fields of a
+// JSON object
+fn block_json_fields(block: &Block) -> Vec<(&'static str, Json)> {
+    let status = block.verify();
+    vec![
+        ("flag", Json::from(block.flag.to_u8())),
+        ("kind", Json::from(block.name())),
+        ("length", Json::from(block.payload.len())),
+        ("header", block.header().as_ref().map(header_json).into()),
+        (
+            "checksum",
+            Json::object([
+                ("stored", Json::from(status.actual)),
+                ("expected", Json::from(status.expected)),
+                ("ok", Json::from(status.is_ok())),
+            ]),
+        ),
+    ]
+}
+
+// Flag, type and name, payload length, header parameters and whether the
+// checksum matches, in the columns of the block tables
+fn block_columns(block: &Block) -> String {
+    let (description, parameters) = match block.header() {
+        Some(header) => (
+            format!("{}: \"{}\"", header.header_type.name(), header.name()),
+            header_parameters(&header),
+        ),
+        None => (block.name().to_string(), String::new()),
+    };
+
+    format!(
+        "{:#04x}  {:<28} {:>6}  {:<24} {}",
+        block.flag.to_u8(),
+        description,
+        block.payload.len(),
+        parameters,
+        if block.verify().is_ok() { "OK" } else { "BAD" },
+    )
+}
 
-        Ok(array_params)
+// One line per block: index, offset in the file and the block columns
+fn print_block_table(tape: &Tape, with_offsets: bool) {
+    println!(
+        "{:>4} {:>7} {:>4}  {:<28} {:>6}  {:<24} checksum",
+        "#", "offset", "flag", "type", "length", "parameters"
+    );
+
+    let mut offset = 0;
+    for (index, block) in tape.blocks.iter().enumerate() {
+        println!(
+            "{:>4} {:>7} {}",
+            index,
+            if with_offsets {
+                offset.to_string()
+            } else {
+                "-".to_string()
+            },
+            block_columns(block),
+        );
+        offset += block.file_len();
     }
 }
 
-#[derive(Debug)]
-enum BlockParams {
-    Program(ProgramParams),
-    Array(ArrayParams),
-    Bytes(BytesParams),
+// The ROM block a TZX block carries, if any
+fn rom_block(block: &TzxBlock) -> Option<Block> {
+    block.tap_data().map(Block::from_tape_data)
 }
 
-#[derive(Debug)]
-struct Header {
-    header_type: HeaderTypeEnum,
-    filename: [u8; 10],
-    len_data: u16,
-    params: Option<BlockParams>,
-    checksum: u8,
+// One line per TZX block in file order: index, block ID and the block
+// columns for blocks holding ROM data, a description for the others
+fn print_tzx_table(tzx: &Tzx) {
+    println!("TZX version {}.{:02}", tzx.major, tzx.minor);
+    println!(
+        "{:>4} {:>4} {:>4}  {:<28} {:>6}  {:<24} checksum",
+        "#", "id", "flag", "type", "length", "parameters"
+    );
+
+    for (index, block) in tzx.blocks.iter().enumerate() {
+        match rom_block(block) {
+            Some(rom) => println!("{:>4} {:#04x} {}", index, block.id(), block_columns(&rom)),
+            None => println!(
+                "{:>4} {:#04x} {:4}  {}",
+                index,
+                block.id(),
+                "",
+                block.describe()
+            ),
+        }
+    }
+}
+
+// The type specific header fields in the form SAVE takes them
+fn header_parameters(header: &Header) -> String {
+    match &header.params {
+        BlockParams::Program(params) if params.autostart_line < tap::NO_AUTOSTART => format!(
+            "LINE {}, {} of {} bytes",
+            params.autostart_line, params.len_program, header.len_data
+        ),
+        BlockParams::Program(params) => {
+            format!("{} of {} bytes", params.len_program, header.len_data)
+        }
+        BlockParams::Bytes(params) => {
+            format!("CODE {},{}", params.start_address, header.len_data)
+        }
+        BlockParams::Array(params) => {
+            format!("DATA {}(), {} bytes", params.name(), header.len_data)
+        }
+        BlockParams::Unknown(params) => format!("{:02x?}, {} bytes", params, header.len_data),
+    }
 }
 
-impl Header {
-    fn from_bytes(reader: &mut BufReader<File>) -> Result<Header, Error> {
-        let header_type = HeaderTypeEnum::from_u8(reader.read_u8()?)
-            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid header type"))?;
+// Describes every block of a tape, as a table or as JSON with a stable schema:
+// {"file", "format", "blocks": [{"index", "offset", "flag", "kind", "length",
+// "header", "checksum": {"stored", "expected", "ok"}}]}. The blocks of a TZX
+// file are listed as stored, each with its "id" and "description", and the
+// fields from "flag" on only when it holds a ROM block.
+fn info(args: &Args) -> io::Result<()> {
+    let [input] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape info <file> [--json]",
+        ));
+    };
 
-        let mut filename = [0; 10];
-        reader.read_exact(&mut filename)?;
+    let image = TapeImage::open(input)?;
+    if !args.option("json", false)? {
+        match &image {
+            TapeImage::Tap(tape) => print_block_table(tape, true),
+            TapeImage::Tzx(tzx) => print_tzx_table(tzx),
+            // Offsets are only known for TAP files, where the blocks are
+            // stored back to back
+            TapeImage::Csw(_) => print_block_table(&image.to_tape(), false),
+        }
+        return Ok(());
+    }
+
+    let (format, blocks) = match &image {
+        TapeImage::Tap(tape) => ("tap", tap_json_blocks(tape, true)),
+        TapeImage::Tzx(tzx) => ("tzx", tzx_json_blocks(tzx)),
+        TapeImage::Csw(_) => ("csw", tap_json_blocks(&image.to_tape(), false)),
+    };
 
-        let len_data = reader.read_u16::<LittleEndian>()?;
+    println!(
+        "{}",
+        Json::object([
+            ("file", Json::from(input.as_str())),
+            ("format", Json::from(format)),
+            ("blocks", Json::Array(blocks)),
+        ])
+    );
+    Ok(())
+}
 
-        let params = match header_type {
-            HeaderTypeEnum::Program => {
-                Some(BlockParams::Program(ProgramParams::from_bytes(reader)?))
+// The blocks of a tape as `info` lists them in JSON
+fn tap_json_blocks(tape: &Tape, with_offsets: bool) -> Vec<Json> {
+    let mut offset = 0;
+    let mut blocks = Vec::new();
+    for (index, block) in tape.blocks.iter().enumerate() {
+        let mut fields = vec![
+            ("index", Json::from(index)),
+            ("offset", Json::from(Some(offset).filter(|_| with_offsets))),
+        ];
+        fields.extend(block_json_fields(block));
+        blocks.push(Json::object(fields));
+        offset += block.file_len();
+    }
+    blocks
+}
+
+// The blocks of a TZX file as `info` lists them in JSON
+fn tzx_json_blocks(tzx: &Tzx) -> Vec<Json> {
+    tzx.blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| {
+            let mut fields = vec![
+                ("index", Json::from(index)),
+                ("offset", Json::Null),
+                ("id", Json::from(block.id())),
+                ("description", Json::from(block.describe())),
+            ];
+            if let Some(rom) = rom_block(block) {
+                fields.extend(block_json_fields(&rom));
             }
-            HeaderTypeEnum::NumArray | HeaderTypeEnum::CharArray => {
-                Some(BlockParams::Array(ArrayParams::from_bytes(reader)?))
+            Json::object(fields)
+        })
+        .collect()
+}
+
+fn export_wav(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape export-wav <input.tap|input.tzx> <output.wav> [--pause <ms>] \
+             [--speed <factor>] [--pilot-only] [--rate <hz>] [--bits <8|16|24|32>] \
+             [--amplitude <0.0-1.0>] [--invert]",
+        ));
+    };
+
+    let samples = sample_options(args)?;
+    let pulses = TapeImage::open(input)?.pulses(&pulse_options(args)?)?;
+    wav::export_wav(output, &pulses, &samples)
+}
+
+// Samples the tape into a CSW file, version 1 with RLE or version 2 with Z-RLE
+fn export_csw(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape export-csw <input.tap|input.tzx> <output.csw> [--version <1|2>] \
+             [--rate <hz>] [--pause <ms>] [--speed <factor>] [--pilot-only]",
+        ));
+    };
+
+    let sample_rate = args.option("rate", SampleOptions::default().sample_rate)?;
+    if sample_rate == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The sample rate must be above 0 Hz",
+        ));
+    }
+
+    let pulses = TapeImage::open(input)?.pulses(&pulse_options(args)?)?;
+    Csw::from_pulses(&pulses, sample_rate).save(output, args.option("version", 2)?)
+}
+
+// Converts a TZX file to TAP, keeping every block stored in the ROM format
+fn convert(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape convert <input.tzx> <output.tap>",
+        ));
+    };
+
+    write_tap(output, &read_tzx(input)?.tap_blocks())
+}
+
+// Recovers the blocks of a tape recording and saves them as a TAP file
+fn decode(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape decode <input.wav|input.csw> <output.tap> [--channel <n>]",
+        ));
+    };
+
+    let mut reader = BufReader::new(File::open(input)?);
+    let blocks = if reader.fill_buf()?.starts_with(csw::SIGNATURE) {
+        Csw::from_bytes(&mut reader)?.decode()
+    } else {
+        decode::decode_wav(reader, args.option("channel", 0)?)?
+    };
+
+    for (index, block) in blocks.iter().enumerate() {
+        let quality = &block.quality;
+        let tap_block = block.block();
+        println!(
+            "{:4}: {:8.2}s flag {:#04x} {:6} bytes checksum {} | pilot {} speed {:+.1}% jitter {:.1}% ambiguous {} trailing bits {}",
+            index,
+            block.position,
+            tap_block.flag.to_u8(),
+            tap_block.payload.len(),
+            if block.checksum_ok { "OK " } else { "BAD" },
+            quality.pilot_pulses,
+            (quality.speed - 1.0) * 100.0,
+            quality.jitter,
+            quality.ambiguous_bits,
+            quality.trailing_bits,
+        );
+    }
+
+    // An empty tape would look like a successful run in batch jobs
+    if blocks.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No blocks found in {}", input),
+        ));
+    }
+
+    let tape_blocks: Vec<Vec<u8>> = blocks.into_iter().map(|block| block.data).collect();
+    write_tap(output, &tape_blocks)
+}
+
+// Exit codes of `verify`, apart from the 1 of an error that stops any command
+const EXIT_UNREADABLE: i32 = 2;
+const EXIT_BAD_BLOCKS: i32 = 3;
+
+// Checks the checksum of every block of the given tapes. Exits with
+// `EXIT_UNREADABLE` if any file could not be read, or else `EXIT_BAD_BLOCKS`
+// if any block is corrupted.
+fn verify(args: &Args) -> io::Result<()> {
+    if args.positional.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape verify <file>...",
+        ));
+    }
+
+    let (mut good, mut bad, mut unreadable) = (0, 0, 0);
+
+    for filename in &args.positional {
+        let image = match TapeImage::open(filename) {
+            Ok(image) => image,
+            Err(error) => {
+                println!("{}: {}", filename, error);
+                unreadable += 1;
+                continue;
             }
-            HeaderTypeEnum::Bytes => Some(BlockParams::Bytes(BytesParams::from_bytes(reader)?)),
         };
 
-        let checksum = reader.read_u8()?;
+        for (index, block) in image.to_tape().blocks.iter().enumerate() {
+            let status = block.verify();
+            println!(
+                "{}: block {:3} flag {:#04x} {:6} bytes checksum {:#04x} expected {:#04x} {}",
+                filename,
+                index,
+                block.flag.to_u8(),
+                block.payload.len(),
+                status.actual,
+                status.expected,
+                if status.is_ok() { "OK" } else { "BAD" },
+            );
 
-        Ok(Header {
-            header_type,
-            filename,
-            len_data,
-            params,
-            checksum,
-        })
+            if status.is_ok() {
+                good += 1;
+            } else {
+                bad += 1;
+            }
+        }
     }
-}
 
-#[derive(Debug)]
-struct Block {
-    len_block: u16,
-    flag: FlagEnum,
-    header: Option<Header>,
-    data: Option<Vec<u8>>,
-    headerless_data: Option<Vec<u8>>,
+    println!(
+        "{} good, {} bad, {} unreadable file(s)",
+        good, bad, unreadable
+    );
+
+    if unreadable > 0 {
+        process::exit(EXIT_UNREADABLE);
+    }
+    if bad > 0 {
+        process::exit(EXIT_BAD_BLOCKS);
+    }
+    Ok(())
 }
 
-impl Block {
-    fn from_bytes(reader: &mut BufReader<File>) -> Result<Block, Error> {
-        let mut blocks: Vec<Block> = Vec::new();
-        let len_block = reader.read_u16::<LittleEndian>()?;
-        let flag = FlagEnum::from_u8(reader.read_u8()?)
-            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid flag"))?;
+// Lists every BASIC program on a tape, with its variables
+fn list(args: &Args) -> io::Result<()> {
+    let [input] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape list <file>",
+        ));
+    };
 
-        let mut header = None;
-        let mut data = None;
+    let tape = TapeImage::open(input)?.to_tape();
+    for file in tape.files() {
+        let (Some(header), Some(data)) = (&file.header, file.data) else {
+            continue;
+        };
+        let BlockParams::Program(params) = &header.params else {
+            continue;
+        };
 
-        if len_block == 0x13 && flag == FlagEnum::Header {
-            header = match flag {
-                FlagEnum::Header => Some(Header::from_bytes(reader)?),
-                FlagEnum::Data => None,
-            };
+        print!("Program: \"{}\"", header.name());
+        if params.autostart_line < tap::NO_AUTOSTART {
+            print!(" LINE {}", params.autostart_line);
         }
+        println!();
 
-        if len_block == 0x13 {
-            let mut block_data = vec![0; (header.as_ref().unwrap().len_data + 4) as usize];
-            reader.read_exact(&mut block_data)?;
-            data = Some(block_data);
+        match Program::from_bytes(&data.payload, params.len_program) {
+            Ok(program) => print!("{}", program),
+            Err(error) => println!("Block {}: {}", file.index, error),
         }
+        println!();
+    }
 
-        let headerless_data = match flag {
-            FlagEnum::Header => None,
-            FlagEnum::Data => {
-                let mut headerless_data = vec![0; (len_block - 1) as usize];
-                reader.read_exact(&mut headerless_data)?;
-                Some(headerless_data)
-            }
+    Ok(())
+}
+
+// Tokenizes a BASIC listing into a program file, ready to LOAD ""
+fn tokenize(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape tokenize <input.bas> <output.tap> [--name <name>] [--autostart <line>]",
+        ));
+    };
+
+    let default_name = Path::new(input)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name: String = args.option("name", default_name)?;
+    let autostart: Option<u16> = match args.option("autostart", String::new())? {
+        line if line.is_empty() => None,
+        line => Some(line.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid value for --autostart: {}", line),
+            )
+        })?),
+    };
+
+    let program = Program::from_source(&fs::read_to_string(input)?)?;
+    let tape = Tape {
+        blocks: program.to_blocks(&name, autostart)?.into(),
+    };
+    tape.save(output)
+}
+
+// Saves every loading screen on a tape as a PNG image: code blocks loaded to
+// the screen memory, and headerless blocks of the right length
+fn screens(args: &Args) -> io::Result<()> {
+    let [input] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape screens <file> [--output-dir <dir>] [--flash]",
+        ));
+    };
+    let output_dir: String = args.option("output-dir", ".".to_string())?;
+    let animate_flash = args.option("flash", false)?;
+
+    let tape = TapeImage::open(input)?.to_tape();
+    for file in tape.files() {
+        let Some(data) = file.data else {
+            continue;
+        };
+        let name = match &file.header {
+            Some(header) if screen::is_screen(header) => file_name(&file),
+            None if data.payload.len() == screen::SCREEN_LEN => "headerless".to_string(),
+            _ => continue,
         };
 
-        Ok(Block {
-            len_block,
-            flag,
-            header,
-            data,
-            headerless_data,
-        })
+        let path = Path::new(&output_dir).join(format!("{:03}-{}.png", file.index, name));
+        Screen::from_bytes(&data.payload)?.save_png(&path, animate_flash)?;
+        println!("{}", path.display());
     }
+
+    Ok(())
 }
 
-// This function converts the binary data into a vector of f32 samples representing audio pulses
-fn convert_bits_to_pulses(data: &[u8], sample_rate: u32) -> Vec<f32> {
-    let mut pulses = Vec::new();
+// Converts a 256x192 PNG image into a SCREEN$ file, written as a new tape or
+// added to the end of an existing one
+fn import_screen(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape import-screen <input.png> <output.tap> [--name <name>] [--append]",
+        ));
+    };
+    let name: String = args.option("name", "screen".to_string())?;
 
-    // Define pulse frequencies and durations (in microseconds)
-    let freq_zero = 1500.0; // Frequency for 0 bit
-    let freq_one = 3000.0; // Frequency for 1 bit
-    let duration_zero = 855.0; // Duration for 0 bit in microseconds
-    let duration_one = 1710.0; // Duration for 1 bit in microseconds
+    let screen = Screen::open_png(input)?;
+    let mut tape = if args.option("append", false)? {
+        Tape::open(output)?
+    } else {
+        Tape { blocks: Vec::new() }
+    };
+    tape.blocks.extend(screen.to_blocks(&name));
+    tape.save(output)
+}
 
-    for &byte in data {
-        for i in 0..8 {
-            let bit = (byte >> i) & 1;
-            let (freq, duration) = if bit == 0 {
-                (freq_zero, duration_zero)
-            } else {
-                (freq_one, duration_one)
-            };
-
-            // Convert duration from microseconds to sample count
-            let sample_count = (duration / 1_000_000.0) * sample_rate as f32;
-
-            // Generate the square wave for the bit
-            for s in 0..sample_count as usize {
-                let value = if (s as f32 * freq / sample_rate as f32 * 2.0 * std::f32::consts::PI)
-                    .sin()
-                    > 0.0
-                {
-                    1.0
-                } else {
-                    -1.0
-                };
-                pulses.push(value);
-            }
-        }
+// The elements as nested JSON arrays, one level per dimension. The last
+// dimension of a character array becomes a string.
+fn array_values_json(array: &Array) -> Json {
+    let mut values: Vec<Json> = match &array.values {
+        ArrayValues::Numbers(numbers) => numbers.iter().map(|&n| Json::Number(n)).collect(),
+        ArrayValues::Chars(_) => array
+            .rows()
+            .into_iter()
+            .map(|mut row| Json::String(row.remove(0)))
+            .collect(),
+    };
+
+    let nested = match &array.values {
+        ArrayValues::Numbers(_) => &array.dimensions[..],
+        ArrayValues::Chars(_) => &array.dimensions[..array.dimensions.len().saturating_sub(1)],
+    };
+    // Group from the innermost dimension outwards
+    for &size in nested.iter().skip(1).rev() {
+        values = values
+            .chunks(size.max(1) as usize)
+            .map(|chunk| Json::Array(chunk.to_vec()))
+            .collect();
     }
 
-    pulses
+    match values.len() {
+        1 if nested.is_empty() => values.remove(0),
+        _ => Json::Array(values),
+    }
 }
 
-fn play_audio_data(data: &[f32]) {
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let source = rodio::buffer::SamplesBuffer::new(1, 44100, data);
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    sink.append(source);
-    sink.sleep_until_end();
+// The array as a JSON object with its name and dimensions
+fn array_json(array: &Array, name: &str) -> Json {
+    Json::object([
+        ("name", Json::from(name)),
+        ("dimensions", Json::from(array.dimensions.clone())),
+        ("values", array_values_json(array)),
+    ])
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        return Err(Error::new(ErrorKind::InvalidInput, "No file name provided"));
+// Writes every array file on a tape as JSON or CSV
+fn arrays(args: &Args) -> io::Result<()> {
+    let [input] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape arrays <file> [--output-dir <dir>] [--format <json|csv>]",
+        ));
+    };
+    let output_dir: String = args.option("output-dir", ".".to_string())?;
+    let format: String = args.option("format", "json".to_string())?;
+    if format != "json" && format != "csv" {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid value for --format: {}", format),
+        ));
     }
 
-    let filename = &args[1];
-    let file = File::open(filename)?;
-    let mut reader = BufReader::new(file);
+    let tape = TapeImage::open(input)?.to_tape();
+    for file in tape.files() {
+        let (Some(header), Some(data)) = (&file.header, file.data) else {
+            continue;
+        };
+        let BlockParams::Array(params) = &header.params else {
+            continue;
+        };
 
-    let mut blocks: Vec<Block> = Vec::new();
+        let numeric = header.header_type == HeaderTypeEnum::NumArray;
+        let array = match Array::from_bytes(&mut data.payload.as_slice(), numeric) {
+            Ok(array) => array,
+            Err(error) => {
+                println!("Block {}: {}", file.index, error);
+                continue;
+            }
+        };
+        let name = params.name();
+        let contents = if format == "json" {
+            format!("{}\n", array_json(&array, &name))
+        } else {
+            array.to_csv()
+        };
 
-    while !reader.fill_buf()?.is_empty() {
-        let block = Block::from_bytes(&mut reader)?;
-        println!("{:?}", block);
-        blocks.push(block);
+        let path = Path::new(&output_dir).join(format!(
+            "{:03}-{}.{}",
+            file.index,
+            file_name(&file),
+            format
+        ));
+        fs::write(&path, contents)?;
+        let dimensions: Vec<String> = array.dimensions.iter().map(u16::to_string).collect();
+        println!("{}: {}({})", path.display(), name, dimensions.join(","));
     }
 
-    for block in blocks {
-        if let Some(data) = block.data {
-            let audio_data = convert_bits_to_pulses(&data, 44100); // 44.1 kHz sample rate
-            play_audio_data(&audio_data);
+    Ok(())
+}
+
+// Writes the data of every file on a tape to a .bin file, with a .json file
+// next to it describing the header and checksums
+fn extract(args: &Args) -> io::Result<()> {
+    let [input] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape extract <file> [--output-dir <dir>]",
+        ));
+    };
+    let output_dir: String = args.option("output-dir", ".".to_string())?;
+
+    let tape = TapeImage::open(input)?.to_tape();
+    let mut used_names = HashSet::new();
+
+    for file in tape.files() {
+        let name = file_name(&file);
+        // Tapes often hold several files of the same name, and some file
+        // systems ignore case
+        let mut unique_name = name.clone();
+        let mut count = 1;
+        while !used_names.insert(unique_name.to_lowercase()) {
+            unique_name = format!("{}-{}", name, count);
+            count += 1;
+        }
+        let path =
+            |extension: &str| Path::new(&output_dir).join(format!("{}.{}", unique_name, extension));
+
+        let mut entries = vec![("index", Json::from(file.index))];
+        if let Some(header) = &file.header {
+            entries.push(("header", header_json(header)));
+            entries.push((
+                "header_checksum_ok",
+                Json::from(tape.blocks[file.index].verify().is_ok()),
+            ));
         }
+        if let Some(data) = file.data {
+            let status = data.verify();
+            entries.push(("flag", Json::from(data.flag.to_u8())));
+            entries.push(("data_length", Json::from(data.payload.len())));
+            entries.push(("checksum", Json::from(status.actual)));
+            entries.push(("expected_checksum", Json::from(status.expected)));
+            entries.push(("checksum_ok", Json::from(status.is_ok())));
+            fs::write(path("bin"), &data.payload)?;
+        }
+
+        fs::write(path("json"), format!("{}\n", Json::object(entries)))?;
+        println!("{}", path("json").display());
     }
 
     Ok(())
 }
+
+// Wraps a binary into a code file, optionally after a BASIC loader that
+// loads and runs it
+fn create(args: &Args) -> io::Result<()> {
+    let [input, output] = args.positional.as_slice() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: zxtape create <input.bin> <output.tap> [--start <address>] [--name <name>] \
+             [--loader] [--clear <address>] [--usr <address>]",
+        ));
+    };
+
+    let default_name = Path::new(input)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name: String = args.option("name", default_name)?;
+    let start: u16 = args.option("start", 32768)?;
+
+    let mut tape = Tape { blocks: Vec::new() };
+    if args.option("loader", false)? {
+        let clear = args.option("clear", start.saturating_sub(1))?;
+        let usr = args.option("usr", start)?;
+        let loader = basic::code_loader(clear, usr)?;
+        tape.blocks.extend(loader.to_blocks(&name, Some(10))?);
+    }
+    tape.blocks
+        .extend(tap::code_file(&name, start, fs::read(input)?)?);
+    tape.save(output)
+}
+
+// The header name of a file on a tape, safe to use in a file name.
+// Headerless blocks, and blank names that would make hidden files like
+// ".bin", are named by their position.
+fn file_name(file: &TapeFile) -> String {
+    let name = file
+        .header
+        .as_ref()
+        .map(|header| safe_filename(&header.name()))
+        .unwrap_or_default();
+    if name.is_empty() {
+        format!("block-{:03}", file.index)
+    } else {
+        name
+    }
+}
+
+// Keeps letters, digits, dashes and dots of a tape file name, so it can be
+// used as part of a file name on any system
+fn safe_filename(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn write_tap(filename: &str, tape_blocks: &[Vec<u8>]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    tap::write_tape_blocks(&mut writer, tape_blocks)?;
+    writer.flush()
+}
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let command = args
+        .next()
+        .ok_or(Error::new(ErrorKind::InvalidInput, "No file name provided"))?;
+
+    match command.as_str() {
+        "export-wav" => export_wav(&Args::parse(args)?),
+        "export-csw" => export_csw(&Args::parse(args)?),
+        "convert" => convert(&Args::parse(args)?),
+        "decode" => decode(&Args::parse(args)?),
+        "verify" => verify(&Args::parse(args)?),
+        "list" => list(&Args::parse(args)?),
+        "tokenize" => tokenize(&Args::parse(args)?),
+        "screens" => screens(&Args::parse(args)?),
+        "import-screen" => import_screen(&Args::parse(args)?),
+        "arrays" => arrays(&Args::parse(args)?),
+        "extract" => extract(&Args::parse(args)?),
+        "create" => create(&Args::parse(args)?),
+        "info" => info(&Args::parse(args)?),
+        #[cfg(feature = "playback")]
+        "devices" => devices(&Args::parse(args)?),
+        // Anything else is the file to play, followed by its options
+        _ => play(&Args::parse(env::args().skip(1))?),
+    }
+}
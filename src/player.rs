@@ -0,0 +1,330 @@
+//! Playing tapes through the sound card
+
+use crate::pulse::{Pulse, PulseOptions, PulseWalk, SampleOptions};
+use crate::TapeImage;
+use rodio::cpal::traits::HostTrait;
+use rodio::{
+    cpal, Device, DeviceTrait, OutputStream, OutputStreamHandle, Sink, Source, StreamError,
+};
+use std::io::{self, Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const NO_SEEK: usize = usize::MAX;
+
+/// Shared between a playing [`TapeSource`] and whoever controls it: reports
+/// the block being played and stops at blocks that stop the tape, and takes
+/// seek requests. Block numbers are positions in
+/// [`TapeImage::playback_order`].
+#[derive(Debug)]
+pub struct Transport {
+    block: AtomicUsize,
+    block_samples: AtomicU64,
+    seek: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl Transport {
+    fn new() -> Self {
+        Transport {
+            block: AtomicUsize::new(0),
+            block_samples: AtomicU64::new(0),
+            seek: AtomicUsize::new(NO_SEEK),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// The block being played
+    pub fn block(&self) -> usize {
+        self.block.load(Ordering::Relaxed)
+    }
+
+    /// Samples played since the start of the current block
+    pub fn block_samples(&self) -> u64 {
+        self.block_samples.load(Ordering::Relaxed)
+    }
+
+    /// Continues playback at the start of `block`
+    pub fn seek(&self, block: usize) {
+        self.seek.store(block, Ordering::Relaxed);
+        self.stopped.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether playback is held after a block that stops the tape, see
+    /// [`TapeImage::stops_tape`]. The source plays silence until resumed.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Continues playback held by [`Transport::is_stopped`] with the next block
+    pub fn resume(&self) {
+        self.stopped.store(false, Ordering::Relaxed);
+    }
+}
+
+/// A tape as an audio source. The pulses of a block are only generated when
+/// playback reaches it and samples are produced as they are pulled, so
+/// memory use doesn't grow with the length of the tape.
+pub struct TapeSource {
+    image: Arc<TapeImage>,
+    order: Vec<usize>,
+    sample_rate: u32,
+    /// Sample values of the high and low levels
+    high: f32,
+    low: f32,
+    options: PulseOptions,
+    /// Position in `order` of the block being played
+    position: usize,
+    pulses: Vec<Pulse>,
+    next_pulse: usize,
+    transport: Arc<Transport>,
+    block_start: u64,
+    /// Sample index where playback started, or continued after a seek
+    origin: u64,
+    /// Pulses played since `origin`
+    walk: PulseWalk,
+    /// Sample index at which the current pulse ends
+    pulse_end: u64,
+    sample: u64,
+}
+
+impl TapeSource {
+    /// Plays the blocks of `image` from the first one, rendered with `options`
+    pub fn new(image: Arc<TapeImage>, options: PulseOptions, samples: &SampleOptions) -> Self {
+        let order = image.playback_order();
+        TapeSource {
+            image,
+            order,
+            sample_rate: samples.sample_rate,
+            high: samples.level_sample(true),
+            low: samples.level_sample(false),
+            options,
+            position: 0,
+            pulses: Vec::new(),
+            next_pulse: 0,
+            transport: Arc::new(Transport::new()),
+            block_start: 0,
+            origin: 0,
+            walk: PulseWalk::new(samples.sample_rate),
+            pulse_end: 0,
+            sample: 0,
+        }
+    }
+
+    /// Number of blocks in playback order
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Whether the tape has no blocks to play
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Handle to follow and control playback once the source is playing
+    pub fn transport(&self) -> Arc<Transport> {
+        Arc::clone(&self.transport)
+    }
+
+    // Drops the rest of the current block and continues with another one
+    fn jump(&mut self, position: usize) {
+        self.position = position;
+        self.pulses.clear();
+        self.next_pulse = 0;
+        // Start from a low level as if the block was the first on the tape
+        self.origin = self.sample;
+        self.walk = PulseWalk::new(self.sample_rate);
+        self.pulse_end = self.sample;
+    }
+
+    // Moves on to the next pulse, rendering the next block when the current
+    // one is used up. Returns false at the end of the tape, and stops the
+    // transport without moving on after a block that stops the tape.
+    fn advance(&mut self) -> bool {
+        while self.next_pulse == self.pulses.len() {
+            let Some(&index) = self.order.get(self.position) else {
+                return false;
+            };
+            // A block that can't be rendered is skipped, like a damaged
+            // stretch of a real tape
+            self.pulses = self
+                .image
+                .block_pulses(index, &self.options)
+                .unwrap_or_default();
+            self.next_pulse = 0;
+            self.transport.block.store(self.position, Ordering::Relaxed);
+            self.block_start = self.sample;
+            self.position += 1;
+            if self.image.stops_tape(index) {
+                self.transport.stopped.store(true, Ordering::Relaxed);
+                return true;
+            }
+        }
+
+        let end = self.walk.step(self.pulses[self.next_pulse]);
+        self.next_pulse += 1;
+        self.pulse_end = self.origin + end;
+        true
+    }
+}
+
+impl Iterator for TapeSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let seek = self.transport.seek.swap(NO_SEEK, Ordering::Relaxed);
+        if seek != NO_SEEK {
+            self.jump(seek);
+        }
+
+        while self.sample >= self.pulse_end {
+            // The level holds, without the tape moving, until resumed
+            if self.transport.is_stopped() {
+                return Some(if self.walk.level() {
+                    self.high
+                } else {
+                    self.low
+                });
+            }
+            if !self.advance() {
+                return None;
+            }
+        }
+
+        self.sample += 1;
+        self.transport
+            .block_samples
+            .store(self.sample - self.block_start, Ordering::Relaxed);
+        Some(if self.walk.level() {
+            self.high
+        } else {
+            self.low
+        })
+    }
+}
+
+impl Source for TapeSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+fn device_name(device: &Device) -> String {
+    device
+        .name()
+        .unwrap_or_else(|_| "Unnamed device".to_string())
+}
+
+fn stream_error(error: StreamError) -> Error {
+    match error {
+        StreamError::NoDevice => Error::new(ErrorKind::NotFound, "No audio output device found"),
+        error => Error::other(error),
+    }
+}
+
+/// Names of the devices that can play audio, in the order [`open_output`]
+/// numbers them
+pub fn output_devices() -> io::Result<Vec<String>> {
+    let devices = cpal::default_host()
+        .output_devices()
+        .map_err(Error::other)?;
+    Ok(devices.map(|device| device_name(&device)).collect())
+}
+
+/// Name of the device used when none is picked
+pub fn default_output_device() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .map(|device| device_name(&device))
+}
+
+/// Opens an output stream on the device with the given name or number in
+/// [`output_devices`]. Names also match without regard to case or as part of
+/// the full name. Without a device the system default is used.
+pub fn open_output(device: Option<&str>) -> io::Result<(OutputStream, OutputStreamHandle)> {
+    let Some(wanted) = device else {
+        return OutputStream::try_default().map_err(stream_error);
+    };
+
+    let devices: Vec<Device> = cpal::default_host()
+        .output_devices()
+        .map_err(Error::other)?
+        .collect();
+    let names: Vec<String> = devices.iter().map(device_name).collect();
+    let lower = wanted.to_lowercase();
+    let index = wanted
+        .parse::<usize>()
+        .ok()
+        .filter(|&index| index < devices.len())
+        .or_else(|| names.iter().position(|name| name == wanted))
+        .or_else(|| names.iter().position(|name| name.to_lowercase() == lower))
+        .or_else(|| {
+            names
+                .iter()
+                .position(|name| name.to_lowercase().contains(&lower))
+        })
+        .ok_or(Error::new(
+            ErrorKind::NotFound,
+            format!("No audio output device matches \"{}\"", wanted),
+        ))?;
+
+    OutputStream::try_from_device(&devices[index]).map_err(stream_error)
+}
+
+/// A sink on an open output stream
+pub fn new_sink(stream: &OutputStreamHandle) -> io::Result<Sink> {
+    Sink::try_new(stream)
+        .map_err(|_| Error::new(ErrorKind::NotFound, "The audio output device was lost"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tzx::{Tzx, TzxBlock};
+
+    #[test]
+    fn stop_blocks_hold_playback_until_resumed() {
+        let data = TzxBlock::StandardSpeed {
+            pause: 0,
+            data: vec![0xFF, 1, 2, 3],
+        };
+        let tzx = Tzx {
+            major: 1,
+            minor: 20,
+            blocks: vec![data.clone(), TzxBlock::Pause(0), data],
+        };
+        let samples = SampleOptions::default();
+        let mut source = TapeSource::new(
+            Arc::new(TapeImage::Tzx(tzx)),
+            PulseOptions::default(),
+            &samples,
+        );
+        let transport = source.transport();
+
+        while !transport.is_stopped() {
+            source.next().unwrap();
+        }
+        assert_eq!(transport.block(), 1);
+        let held = source.next().unwrap();
+        assert!(source.by_ref().take(10_000).all(|sample| sample == held));
+        assert_eq!(transport.block(), 1);
+
+        transport.resume();
+        assert!(source.by_ref().count() > 0);
+        assert_eq!(transport.block(), 2);
+        assert!(!transport.is_stopped());
+    }
+}
@@ -0,0 +1,146 @@
+//! PNG and APNG output for images with a small palette, and PNG input for
+//! images of any colour type
+
+use ::png::{BitDepth, ColorType, Compression, Limits, Transformations};
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+/// Most the decoder may allocate besides the image itself
+const MAX_ALLOCATION: usize = 1 << 20;
+
+/// An image made of palette indices, one byte per pixel
+#[derive(Debug, Clone)]
+pub(crate) struct IndexedImage {
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Up to 256 RGB colours the indices refer to
+    pub palette: Vec<[u8; 3]>,
+    /// One or more frames of `width * height` indices; more than one frame
+    /// is written as an animated PNG
+    pub frames: Vec<Vec<u8>>,
+    /// How long each frame of an animation is shown, in milliseconds
+    pub frame_delay: u16,
+}
+
+impl IndexedImage {
+    /// Writes the image as a PNG, or an animated PNG when it has several frames
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.palette.is_empty() || self.palette.len() > 256 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A palette needs 1 to 256 colours",
+            ));
+        }
+        if self.frames.is_empty()
+            || self
+                .frames
+                .iter()
+                .any(|frame| frame.len() != self.width as usize * self.height as usize)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Frames don't match the image size",
+            ));
+        }
+
+        let mut encoder = ::png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(self.bit_depth());
+        encoder.set_palette(self.palette.concat());
+        encoder.set_compression(Compression::Best);
+        if self.frames.len() > 1 {
+            // Loop forever, each frame replacing the previous one
+            encoder.set_animated(self.frames.len() as u32, 0)?;
+            encoder.set_frame_delay(self.frame_delay, 1000)?;
+        }
+
+        let mut writer = encoder.write_header()?;
+        for frame in &self.frames {
+            writer.write_image_data(&self.packed_rows(frame))?;
+        }
+        Ok(writer.finish()?)
+    }
+
+    fn bit_depth(&self) -> BitDepth {
+        match self.palette.len() {
+            0..=2 => BitDepth::One,
+            3..=4 => BitDepth::Two,
+            5..=16 => BitDepth::Four,
+            _ => BitDepth::Eight,
+        }
+    }
+
+    // Packs the indices of every row into bytes, as many to a byte as the
+    // bit depth allows
+    fn packed_rows(&self, frame: &[u8]) -> Vec<u8> {
+        let depth = self.bit_depth() as usize;
+        let per_byte = 8 / depth;
+        let mut data = Vec::new();
+
+        for row in frame.chunks(self.width as usize) {
+            for pixels in row.chunks(per_byte) {
+                let byte = pixels.iter().enumerate().fold(0u8, |byte, (i, &index)| {
+                    byte | index << (8 - depth * (i + 1))
+                });
+                data.push(byte);
+            }
+        }
+        data
+    }
+}
+
+/// An image with one RGB triple per pixel, row by row
+#[derive(Debug, Clone)]
+pub(crate) struct RgbImage {
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// `width * height` pixels, row by row
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl RgbImage {
+    /// Reads the first frame of a PNG image of exactly `width` by `height`
+    /// pixels. Other sizes are rejected once the header has been read, before
+    /// any image data. Transparency is ignored and 16 bit samples are cut to
+    /// 8 bits.
+    pub fn from_png<R: Read>(reader: R, width: u32, height: u32) -> io::Result<Self> {
+        let mut decoder = ::png::Decoder::new(reader);
+        // Plenty for the chunks of an image this size, so lengths made up by
+        // a broken file fail instead of allocating
+        decoder.set_limits(Limits {
+            bytes: MAX_ALLOCATION,
+        });
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+
+        let info = reader.info();
+        if (info.width, info.height) != (width, height) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The image must be {}x{}, not {}x{}",
+                    width, height, info.width, info.height
+                ),
+            ));
+        }
+
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let output = reader.next_frame(&mut buffer)?;
+        let pixels = buffer[..output.buffer_size()]
+            .chunks_exact(output.color_type.samples())
+            .map(|pixel| match output.color_type {
+                ColorType::Grayscale | ColorType::GrayscaleAlpha => [pixel[0]; 3],
+                _ => [pixel[0], pixel[1], pixel[2]],
+            })
+            .collect();
+
+        Ok(RgbImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
@@ -0,0 +1,367 @@
+//! Pulse generation with the Spectrum ROM SA-BYTES/LD-BYTES timings. All
+//! durations are T-states of the 3.5 MHz Z80 clock and every pulse value is
+//! the length of a single half-wave.
+
+use std::io::{self, Error, ErrorKind};
+
+/// Z80 clock of the 48K Spectrum, in T-states per second
+pub const CPU_CLOCK: u32 = 3_500_000;
+/// Length of a pilot pulse
+pub const PILOT_PULSE: u32 = 2168;
+/// Pilot pulses before a header block
+pub const PILOT_HEADER_PULSES: usize = 8063;
+/// Pilot pulses before a data block
+pub const PILOT_DATA_PULSES: usize = 3223;
+/// First sync pulse, after the pilot
+pub const SYNC1_PULSE: u32 = 667;
+/// Second sync pulse
+pub const SYNC2_PULSE: u32 = 735;
+/// Each of the two pulses of a zero bit
+pub const ZERO_PULSE: u32 = 855;
+/// Each of the two pulses of a one bit
+pub const ONE_PULSE: u32 = 1710;
+
+/// The ROM loader wants at least 256 pilot edges before it looks for sync
+pub const MIN_PILOT_PULSES: usize = 256;
+
+/// T-states in one millisecond, used for pauses
+pub const MILLISECOND: u32 = CPU_CLOCK / 1000;
+
+/// Silence after each block of a TAP file, in milliseconds. TAP files store no
+/// pauses, and without one the next pilot starts while the ROM is still
+/// handling the previous block.
+pub const DEFAULT_PAUSE: u32 = 1000;
+
+/// A stretch of the tape signal, with durations in T-states
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pulse {
+    /// Flip the level, then hold it
+    Edge(u32),
+    /// Keep the current level
+    Hold(u32),
+    /// Force the level, then hold it
+    Level(bool, u32),
+}
+
+/// Changes to the signal of a tape, applied while it is rendered to pulses
+#[derive(Debug, Clone)]
+pub struct PulseOptions {
+    /// Pause after every header and data block in milliseconds, replacing
+    /// both the TAP default and the pauses stored in TZX blocks. TZX blocks
+    /// stored without a pause keep running straight into the next block, as
+    /// multi-part turbo loaders expect, and TZX pause blocks keep their length.
+    pub pause: Option<u32>,
+    /// Turbo factor, 2.0 plays the tape in half the time. Pauses keep their
+    /// length.
+    pub speed: f64,
+    /// Only shortens pilot tones, by sending fewer pilot pulses of the usual
+    /// length, so the tape still loads with a standard ROM
+    pub pilot_only: bool,
+}
+
+impl Default for PulseOptions {
+    fn default() -> Self {
+        PulseOptions {
+            pause: None,
+            speed: 1.0,
+            pilot_only: false,
+        }
+    }
+}
+
+impl PulseOptions {
+    /// Rejects speeds that are not a positive number
+    pub fn validate(&self) -> io::Result<()> {
+        if !(self.speed.is_finite() && self.speed > 0.0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The speed must be a number above 0",
+            ));
+        }
+        Ok(())
+    }
+
+    /// A pulse length after applying the speed. Lengths of 0 stay 0 and
+    /// others never drop below 1 T-state.
+    pub fn duration(&self, tstates: u32) -> u32 {
+        if self.pilot_only || tstates == 0 {
+            tstates
+        } else {
+            ((tstates as f64 / self.speed).round() as u32).max(1)
+        }
+    }
+
+    /// The number of pulses to send for a pilot tone of `count` pulses. Tones
+    /// are never cut below what the ROM loader needs to lock on.
+    pub fn pilot_pulses(&self, count: usize) -> usize {
+        if self.pilot_only {
+            ((count as f64 / self.speed).round() as usize).max(count.min(MIN_PILOT_PULSES))
+        } else {
+            count
+        }
+    }
+
+    /// Block timings with the speed applied
+    pub fn timings(&self, timings: &Timings) -> Timings {
+        Timings {
+            pilot_pulse: self.duration(timings.pilot_pulse),
+            pilot_pulses: self.pilot_pulses(timings.pilot_pulses),
+            sync1_pulse: self.duration(timings.sync1_pulse),
+            sync2_pulse: self.duration(timings.sync2_pulse),
+            zero_pulse: self.duration(timings.zero_pulse),
+            one_pulse: self.duration(timings.one_pulse),
+        }
+    }
+
+    /// The pause to play after a block that asks for `stored` milliseconds
+    pub fn pause_after(&self, stored: u32) -> u32 {
+        match self.pause {
+            Some(pause) if stored > 0 => pause,
+            _ => stored,
+        }
+    }
+}
+
+/// How pulses are turned into samples, for playback as well as WAV files
+#[derive(Debug, Clone)]
+pub struct SampleOptions {
+    /// Samples per second
+    pub sample_rate: u32,
+    /// 8, 16, 24 or 32. Playback is quantized the same way as a WAV file.
+    pub bits_per_sample: u16,
+    /// Peak level of the square wave, from 0.0 to 1.0
+    pub amplitude: f32,
+    /// Plays high levels as negative samples, for interfaces that invert
+    pub inverted: bool,
+}
+
+impl Default for SampleOptions {
+    fn default() -> Self {
+        SampleOptions {
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            amplitude: 1.0,
+            inverted: false,
+        }
+    }
+}
+
+impl SampleOptions {
+    /// Rejects a zero sample rate, unsupported bit depths and amplitudes outside 0.0-1.0
+    pub fn validate(&self) -> io::Result<()> {
+        if self.sample_rate == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The sample rate must be above 0 Hz",
+            ));
+        }
+        if ![8, 16, 24, 32].contains(&self.bits_per_sample) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only 8, 16, 24 and 32 bit samples are supported",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.amplitude) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The amplitude must be between 0.0 and 1.0",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Largest integer sample at the configured bit depth
+    pub fn max_sample(&self) -> i32 {
+        ((1i64 << (self.bits_per_sample.clamp(1, 32) - 1)) - 1) as i32
+    }
+
+    /// The sample for a signal level, rounded to a whole integer step so
+    /// playback sounds exactly like the exported file
+    pub fn level_sample(&self, level: bool) -> f32 {
+        let max = self.max_sample() as f64;
+        let peak = ((self.amplitude as f64 * max).round() / max) as f32;
+        if level != self.inverted {
+            peak
+        } else {
+            -peak
+        }
+    }
+}
+
+/// Pilot, sync and bit lengths of a block. The ROM values are the defaults,
+/// turbo loaders replace some or all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timings {
+    /// Length of a pilot pulse
+    pub pilot_pulse: u32,
+    /// Number of pilot pulses
+    pub pilot_pulses: usize,
+    /// Length of the first sync pulse
+    pub sync1_pulse: u32,
+    /// Length of the second sync pulse
+    pub sync2_pulse: u32,
+    /// Length of each pulse of a zero bit
+    pub zero_pulse: u32,
+    /// Length of each pulse of a one bit
+    pub one_pulse: u32,
+}
+
+impl Timings {
+    /// The ROM uses the long pilot for headers (flag < 0x80) and the short one otherwise
+    pub fn rom(flag: u8) -> Self {
+        Timings {
+            pilot_pulse: PILOT_PULSE,
+            pilot_pulses: if flag < 0x80 {
+                PILOT_HEADER_PULSES
+            } else {
+                PILOT_DATA_PULSES
+            },
+            sync1_pulse: SYNC1_PULSE,
+            sync2_pulse: SYNC2_PULSE,
+            zero_pulse: ZERO_PULSE,
+            one_pulse: ONE_PULSE,
+        }
+    }
+}
+
+/// Generates the pulses the ROM saver produces for one tape block, at the
+/// speed set in `options`. `data` is the block as stored on tape: flag byte,
+/// payload and checksum.
+pub fn block_pulses(data: &[u8], options: &PulseOptions) -> Vec<Pulse> {
+    let timings = options.timings(&Timings::rom(data.first().copied().unwrap_or(0xFF)));
+    let mut pulses = Vec::with_capacity(timings.pilot_pulses + 2 + data.len() * 16);
+    timed_block_pulses(&timings, data, 8, &mut pulses);
+    pulses
+}
+
+/// Pilot tone, sync pulses and data of a block saved with arbitrary timings
+pub fn timed_block_pulses(timings: &Timings, data: &[u8], used_bits: u8, pulses: &mut Vec<Pulse>) {
+    pulses.extend(std::iter::repeat_n(
+        Pulse::Edge(timings.pilot_pulse),
+        timings.pilot_pulses,
+    ));
+    pulses.push(Pulse::Edge(timings.sync1_pulse));
+    pulses.push(Pulse::Edge(timings.sync2_pulse));
+    data_pulses(
+        timings.zero_pulse,
+        timings.one_pulse,
+        data,
+        used_bits,
+        pulses,
+    );
+}
+
+/// Bits go out most significant first, each as two equal half-waves. Only the
+/// top `used_bits` bits of the last byte are sent.
+pub fn data_pulses(
+    zero_pulse: u32,
+    one_pulse: u32,
+    data: &[u8],
+    used_bits: u8,
+    pulses: &mut Vec<Pulse>,
+) {
+    for (index, &byte) in data.iter().enumerate() {
+        let bits = if index + 1 == data.len() {
+            used_bits.clamp(1, 8)
+        } else {
+            8
+        };
+
+        for i in (8 - bits..8).rev() {
+            let pulse = if (byte >> i) & 1 == 0 {
+                zero_pulse
+            } else {
+                one_pulse
+            };
+            pulses.push(Pulse::Edge(pulse));
+            pulses.push(Pulse::Edge(pulse));
+        }
+    }
+}
+
+/// Silence between blocks. The first millisecond flips the level so the last
+/// half-wave of the previous block is terminated by an edge.
+pub fn pause_pulses(milliseconds: u32, pulses: &mut Vec<Pulse>) {
+    if milliseconds > 0 {
+        pulses.push(Pulse::Edge(MILLISECOND));
+        // Long pauses don't fit in one pulse
+        let mut rest = (milliseconds - 1) as u64 * MILLISECOND as u64;
+        while rest > 0 {
+            let duration = rest.min(u32::MAX as u64);
+            pulses.push(Pulse::Level(false, duration as u32));
+            rest -= duration;
+        }
+    }
+}
+
+/// Index of the sample at which `tstates` have passed. The product is kept in
+/// 128 bits so it can't overflow for any rate or tape length.
+pub fn tstates_to_samples(tstates: u64, sample_rate: u32) -> u64 {
+    (tstates as u128 * sample_rate as u128 / CPU_CLOCK as u128) as u64
+}
+
+/// Follows the signal through a sequence of pulses, starting at a low level,
+/// and places the end of every pulse on a sample. The running T-state count
+/// is converted at every pulse, so rounding never accumulates.
+#[derive(Debug, Clone)]
+pub(crate) struct PulseWalk {
+    sample_rate: u32,
+    level: bool,
+    elapsed: u64,
+}
+
+impl PulseWalk {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        PulseWalk {
+            sample_rate,
+            level: false,
+            elapsed: 0,
+        }
+    }
+
+    /// Level of the signal after the last pulse
+    pub(crate) fn level(&self) -> bool {
+        self.level
+    }
+
+    /// Moves past `pulse`, returning the sample index, counted from the start
+    /// of the walk, at which it ends
+    pub(crate) fn step(&mut self, pulse: Pulse) -> u64 {
+        let duration = match pulse {
+            Pulse::Edge(duration) => {
+                self.level = !self.level;
+                duration
+            }
+            Pulse::Hold(duration) => duration,
+            Pulse::Level(level, duration) => {
+                self.level = level;
+                duration
+            }
+        };
+        self.elapsed += duration as u64;
+        tstates_to_samples(self.elapsed, self.sample_rate)
+    }
+}
+
+/// Level and end sample of every pulse, see [`PulseWalk`]
+pub(crate) fn pulse_levels(
+    pulses: &[Pulse],
+    sample_rate: u32,
+) -> impl Iterator<Item = (bool, u64)> + '_ {
+    let mut walk = PulseWalk::new(sample_rate);
+    pulses.iter().map(move |&pulse| {
+        let end = walk.step(pulse);
+        (walk.level(), end)
+    })
+}
+
+/// Converts pulses into a square wave
+pub fn pulses_to_samples(pulses: &[Pulse], options: &SampleOptions) -> Vec<f32> {
+    let high = options.level_sample(true);
+    let low = options.level_sample(false);
+    let mut samples = Vec::new();
+    for (level, end) in pulse_levels(pulses, options.sample_rate) {
+        samples.resize(end as usize, if level { high } else { low });
+    }
+    samples
+}
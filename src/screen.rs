@@ -0,0 +1,355 @@
+//! Spectrum screen memory, as saved with `SAVE "name" SCREEN$`
+
+use crate::png::{IndexedImage, RgbImage};
+use crate::tap::{Block, BlockParams, FlagEnum, Header};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Address of the screen memory
+pub const SCREEN_ADDRESS: u16 = 16384;
+/// Length of the bitmap
+pub const BITMAP_LEN: usize = 6144;
+/// Length of the bitmap and attributes together
+pub const SCREEN_LEN: usize = 6912;
+
+/// Width of the screen in pixels
+pub const WIDTH: u32 = 256;
+/// Height of the screen in pixels
+pub const HEIGHT: u32 = 192;
+
+/// Flashing attributes swap ink and paper every 16 frames of 50 Hz video
+pub const FLASH_PERIOD: u16 = 320;
+
+/// The 8 colours at normal and bright intensity, indexed by colour number
+/// plus 8 for bright
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xD7],
+    [0xD7, 0x00, 0x00],
+    [0xD7, 0x00, 0xD7],
+    [0x00, 0xD7, 0x00],
+    [0x00, 0xD7, 0xD7],
+    [0xD7, 0xD7, 0x00],
+    [0xD7, 0xD7, 0xD7],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xFF],
+    [0xFF, 0x00, 0x00],
+    [0xFF, 0x00, 0xFF],
+    [0x00, 0xFF, 0x00],
+    [0x00, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0x00],
+    [0xFF, 0xFF, 0xFF],
+];
+
+const FLASH: u8 = 0x80;
+const BRIGHT: u8 = 0x40;
+
+/// Whether a header describes a screen: code loaded to the screen memory
+/// and exactly as long as it
+pub fn is_screen(header: &Header) -> bool {
+    matches!(
+        &header.params,
+        BlockParams::Bytes(params)
+            if params.start_address == SCREEN_ADDRESS && header.len_data as usize == SCREEN_LEN
+    )
+}
+
+/// Offset in the bitmap of the byte holding pixel `x`, `y`. The screen is
+/// split into thirds of 64 lines, and within each third the pixel lines of a
+/// character row are 256 bytes apart.
+pub fn bitmap_offset(x: u32, y: u32) -> usize {
+    let (x, y) = (x as usize, y as usize);
+    (y & 0xC0) << 5 | (y & 0x07) << 8 | (y & 0x38) << 2 | x >> 3
+}
+
+/// A screen: the pixel bitmap and one attribute byte per 8x8 character cell
+#[derive(Debug, Clone)]
+pub struct Screen {
+    /// Pixels in the order of screen memory, 8 to a byte
+    pub bitmap: Vec<u8>,
+    /// Colours of each 8x8 cell, row by row
+    pub attributes: Vec<u8>,
+}
+
+impl Screen {
+    /// Splits a 6912-byte screen dump into bitmap and attributes
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.len() != SCREEN_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("A screen is {} bytes, not {}", SCREEN_LEN, data.len()),
+            ));
+        }
+
+        Ok(Screen {
+            bitmap: data[..BITMAP_LEN].to_vec(),
+            attributes: data[BITMAP_LEN..].to_vec(),
+        })
+    }
+
+    // Converts a 256x192 image, picking for every character cell the ink,
+    // paper and brightness that come closest to its pixels
+    pub(crate) fn from_image(image: &RgbImage) -> io::Result<Self> {
+        if image.width != WIDTH || image.height != HEIGHT {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "A screen image must be {}x{}, not {}x{}",
+                    WIDTH, HEIGHT, image.width, image.height
+                ),
+            ));
+        }
+
+        let mut screen = Screen {
+            bitmap: vec![0; BITMAP_LEN],
+            attributes: vec![0; SCREEN_LEN - BITMAP_LEN],
+        };
+
+        for (cell, attribute) in screen.attributes.iter_mut().enumerate() {
+            let (cell_x, cell_y) = (cell as u32 % 32 * 8, cell as u32 / 32 * 8);
+            let pixels: Vec<(u32, u32)> =
+                (0..64).map(|i| (cell_x + i % 8, cell_y + i / 8)).collect();
+
+            // Distance of every pixel of the cell to every palette colour
+            let distances: Vec<[u32; 16]> = pixels
+                .iter()
+                .map(|&(x, y)| {
+                    let pixel = image.pixels[(y * WIDTH + x) as usize];
+                    PALETTE.map(|colour| colour_distance(pixel, colour))
+                })
+                .collect();
+
+            let mut best = (u32::MAX, 0, 0);
+            for bright in [0, 8] {
+                for ink in bright..bright + 8 {
+                    for paper in ink..bright + 8 {
+                        let cost = distances
+                            .iter()
+                            .map(|distance| distance[ink].min(distance[paper]))
+                            .sum();
+                        if cost < best.0 {
+                            best = (cost, ink, paper);
+                        }
+                    }
+                }
+            }
+
+            let (_, ink, paper) = best;
+            *attribute = (paper as u8 & 0x07) << 3 | ink as u8 & 0x07;
+            if ink >= 8 {
+                *attribute |= BRIGHT;
+            }
+
+            for (&(x, y), distance) in pixels.iter().zip(&distances) {
+                if distance[ink] < distance[paper] {
+                    screen.bitmap[bitmap_offset(x, y)] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+
+        Ok(screen)
+    }
+
+    /// Converts a 256x192 PNG image, picking for every character cell the
+    /// ink, paper and brightness that come closest to its pixels
+    pub fn from_png<R: Read>(reader: R) -> io::Result<Self> {
+        Screen::from_image(&RgbImage::from_png(reader, WIDTH, HEIGHT)?)
+    }
+
+    /// Converts a 256x192 PNG file like [`Screen::from_png`]
+    pub fn open_png<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Screen::from_png(BufReader::new(File::open(path)?))
+    }
+
+    /// Bitmap followed by attributes, as saved to tape
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.bitmap.as_slice(), &self.attributes].concat()
+    }
+
+    /// Header and data block of the screen saved as `SCREEN$`
+    pub fn to_blocks(&self, filename: &str) -> [Block; 2] {
+        let header = Header::code(filename, SCREEN_ADDRESS, SCREEN_LEN as u16);
+        [
+            Block::from_header(&header),
+            Block::new(FlagEnum::Data, self.to_bytes()),
+        ]
+    }
+
+    /// Whether any cell has the flash attribute set
+    pub fn has_flash(&self) -> bool {
+        self.attributes
+            .iter()
+            .any(|&attribute| attribute & FLASH != 0)
+    }
+
+    /// Palette index of every pixel, row by row. With `flash_swapped` the
+    /// flashing cells show their ink and paper swapped.
+    pub fn pixels(&self, flash_swapped: bool) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity((WIDTH * HEIGHT) as usize);
+
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let attribute = self.attributes[(y as usize / 8) * 32 + x as usize / 8];
+                let bright = if attribute & BRIGHT != 0 { 8 } else { 0 };
+                let ink = attribute & 0x07 | bright;
+                let paper = (attribute >> 3) & 0x07 | bright;
+
+                let set = self.bitmap[bitmap_offset(x, y)] & (0x80 >> (x % 8)) != 0;
+                let swapped = flash_swapped && attribute & FLASH != 0;
+                pixels.push(if set != swapped { ink } else { paper });
+            }
+        }
+
+        pixels
+    }
+
+    // The screen as an image; with `animate_flash` and flashing cells on the
+    // screen, as a two frame animation
+    pub(crate) fn to_image(&self, animate_flash: bool) -> IndexedImage {
+        let mut frames = vec![self.pixels(false)];
+        if animate_flash && self.has_flash() {
+            frames.push(self.pixels(true));
+        }
+
+        IndexedImage {
+            width: WIDTH,
+            height: HEIGHT,
+            palette: PALETTE.to_vec(),
+            frames,
+            frame_delay: FLASH_PERIOD,
+        }
+    }
+
+    /// Writes the screen as a PNG, or an animated PNG when flashing is animated
+    pub fn write_png<W: Write>(&self, writer: &mut W, animate_flash: bool) -> io::Result<()> {
+        self.to_image(animate_flash).write_to(writer)
+    }
+
+    /// Writes the screen to a PNG file
+    pub fn save_png<P: AsRef<Path>>(&self, path: P, animate_flash: bool) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_png(&mut writer, animate_flash)?;
+        writer.flush()
+    }
+}
+
+fn colour_distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter()
+        .zip(&b)
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A different pattern and colour in every cell, some of them flashing
+    fn test_screen() -> Screen {
+        let data: Vec<u8> = (0..SCREEN_LEN).map(|i| (i * 7 % 251) as u8).collect();
+        Screen::from_bytes(&data).unwrap()
+    }
+
+    // Every frame of a PNG image as RGB pixels
+    fn png_frames(png: &[u8]) -> Vec<Vec<[u8; 3]>> {
+        let mut decoder = ::png::Decoder::new(png);
+        decoder.set_transformations(::png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let count = reader
+            .info()
+            .animation_control()
+            .map_or(1, |control| control.num_frames);
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        (0..count)
+            .map(|_| {
+                let info = reader.next_frame(&mut buffer).unwrap();
+                buffer[..info.buffer_size()]
+                    .chunks_exact(3)
+                    .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flashing_screen_renders_as_two_frames() {
+        let screen = test_screen();
+        assert!(screen.has_flash());
+        let expected: Vec<Vec<[u8; 3]>> = [false, true]
+            .iter()
+            .map(|&swapped| {
+                screen
+                    .pixels(swapped)
+                    .iter()
+                    .map(|&index| PALETTE[index as usize])
+                    .collect()
+            })
+            .collect();
+
+        let mut animated = Vec::new();
+        screen.write_png(&mut animated, true).unwrap();
+        assert_eq!(png_frames(&animated), expected);
+
+        let mut still = Vec::new();
+        screen.write_png(&mut still, false).unwrap();
+        assert_eq!(png_frames(&still), expected[..1]);
+    }
+
+    // A chunk with the given length field, followed by `data` and its CRC
+    fn png_chunk(len: u32, kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut crc = flate2::Crc::new();
+        crc.update(kind);
+        crc.update(data);
+        [&len.to_be_bytes(), kind, data, &crc.sum().to_be_bytes()].concat()
+    }
+
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let ihdr = [
+            &width.to_be_bytes()[..],
+            &height.to_be_bytes(),
+            &[8, 2, 0, 0, 0],
+        ]
+        .concat();
+        [&b"\x89PNG\r\n\x1a\n"[..], &png_chunk(13, b"IHDR", &ihdr)].concat()
+    }
+
+    #[test]
+    fn png_converts_back_to_the_same_pixels() {
+        let screen = test_screen();
+        let mut png = Vec::new();
+        screen.write_png(&mut png, false).unwrap();
+        let converted = Screen::from_png(png.as_slice()).unwrap();
+
+        let colours = |screen: &Screen| -> Vec<[u8; 3]> {
+            screen
+                .pixels(false)
+                .iter()
+                .map(|&index| PALETTE[index as usize])
+                .collect()
+        };
+        assert_eq!(colours(&converted), colours(&screen));
+    }
+
+    #[test]
+    fn other_image_sizes_are_rejected() {
+        let mut png = Vec::new();
+        let mut encoder = ::png::Encoder::new(&mut png, 8, 8);
+        encoder.set_color(::png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0; 8 * 8 * 3]).unwrap();
+        writer.finish().unwrap();
+        assert!(Screen::from_png(png.as_slice()).is_err());
+
+        // Far too large to allocate, refused on the header alone
+        let png = png_header(1 << 30, 1 << 30);
+        assert!(Screen::from_png(png.as_slice()).is_err());
+    }
+
+    #[test]
+    fn huge_chunk_lengths_fail_without_allocating() {
+        let mut png = png_header(WIDTH, HEIGHT);
+        png.extend(png_chunk(0x7FFF_FFF0, b"tEXt", b"a"));
+        assert!(Screen::from_png(png.as_slice()).is_err());
+    }
+}
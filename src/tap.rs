@@ -0,0 +1,847 @@
+//! TAP files: a sequence of length prefixed blocks as saved by the ROM
+
+use crate::basic;
+use crate::pulse::{self, Pulse, PulseOptions};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Flag byte that starts every tape block. The ROM saves 0x00 for headers and
+/// 0xFF for data, custom loaders are free to use any other value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlagEnum {
+    /// 0x00, the flag of header blocks
+    Header,
+    /// 0xFF, the flag of data blocks
+    Data,
+    /// Any other flag, used by custom loaders
+    Custom(u8),
+}
+
+impl FlagEnum {
+    /// Interprets a flag byte, every value is valid
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x00 => FlagEnum::Header,
+            0xFF => FlagEnum::Data,
+            _ => FlagEnum::Custom(value),
+        }
+    }
+
+    /// Short name of the flag for listings
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlagEnum::Header => "Header",
+            FlagEnum::Data => "Data",
+            FlagEnum::Custom(_) => "Custom",
+        }
+    }
+
+    /// The flag byte as stored on tape
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            FlagEnum::Header => 0x00,
+            FlagEnum::Data => 0xFF,
+            FlagEnum::Custom(value) => *value,
+        }
+    }
+}
+
+/// Kind of file described by a header block
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderTypeEnum {
+    /// 0, a BASIC program saved with `SAVE "name"`
+    Program,
+    /// 1, a numeric array saved with `SAVE "name" DATA a()`
+    NumArray,
+    /// 2, a character array saved with `SAVE "name" DATA a$()`
+    CharArray,
+    /// 3, memory saved with `SAVE "name" CODE`
+    Bytes,
+    /// Any other type byte
+    Unknown(u8),
+}
+
+impl HeaderTypeEnum {
+    /// Interprets the type byte of a header, every value is valid
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x00 => HeaderTypeEnum::Program,
+            0x01 => HeaderTypeEnum::NumArray,
+            0x02 => HeaderTypeEnum::CharArray,
+            0x03 => HeaderTypeEnum::Bytes,
+            _ => HeaderTypeEnum::Unknown(value),
+        }
+    }
+
+    /// Name of the type as `LOAD` prints it, e.g. `Program` for "Program: name"
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeaderTypeEnum::Program => "Program",
+            HeaderTypeEnum::NumArray => "Number array",
+            HeaderTypeEnum::CharArray => "Character array",
+            HeaderTypeEnum::Bytes => "Bytes",
+            HeaderTypeEnum::Unknown(_) => "Unknown",
+        }
+    }
+
+    /// The type byte as stored in the header
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            HeaderTypeEnum::Program => 0x00,
+            HeaderTypeEnum::NumArray => 0x01,
+            HeaderTypeEnum::CharArray => 0x02,
+            HeaderTypeEnum::Bytes => 0x03,
+            HeaderTypeEnum::Unknown(value) => *value,
+        }
+    }
+}
+
+/// Length of a header block payload
+pub const HEADER_LEN: usize = 17;
+
+/// Autostart line of programs that don't start themselves
+pub const NO_AUTOSTART: u16 = 32768;
+
+/// Header parameters of a BASIC program
+#[derive(Debug)]
+pub struct ProgramParams {
+    /// Line to `RUN` after loading, 32768 or more for none
+    pub autostart_line: u16,
+    /// Length of the program without its variables
+    pub len_program: u16,
+}
+
+impl ProgramParams {
+    /// Reads the 4 parameter bytes of a program header
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(ProgramParams {
+            autostart_line: reader.read_u16::<LittleEndian>()?,
+            len_program: reader.read_u16::<LittleEndian>()?,
+        })
+    }
+
+    /// Writes the 4 parameter bytes
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u16::<LittleEndian>(self.autostart_line)?;
+        writer.write_u16::<LittleEndian>(self.len_program)
+    }
+}
+
+/// Header parameters of a block of code
+#[derive(Debug)]
+pub struct BytesParams {
+    /// Address the code is loaded to by default
+    pub start_address: u16,
+    /// Ignored when loading, the ROM saves 32768 here
+    pub reserved: [u8; 2],
+}
+
+impl BytesParams {
+    /// Reads the 4 parameter bytes of a code header
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let bytes_params = BytesParams {
+            start_address: reader.read_u16::<LittleEndian>()?,
+            reserved: [reader.read_u8()?, reader.read_u8()?],
+        };
+        // if !bytes_params.reserved.iter().all(|&x| x == 0) {
+        //     return Err(Error::new(ErrorKind::InvalidData, "Invalid bytes params"));
+        // }
+
+        Ok(bytes_params)
+    }
+
+    /// Writes the 4 parameter bytes
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u16::<LittleEndian>(self.start_address)?;
+        writer.write_all(&self.reserved)
+    }
+}
+
+/// Header parameters of a numeric or character array
+#[derive(Debug)]
+pub struct ArrayParams {
+    /// Unused by the ROM
+    pub reserved: u8,
+    /// Array name in the form it has in the variables area
+    pub var_name: u8,
+    /// Unused by the ROM, often left over from memory
+    pub reserved1: [u8; 2],
+}
+
+impl ArrayParams {
+    /// Reads the 4 parameter bytes of an array header
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        // The reserved bytes are often left over from memory, so they're kept
+        // rather than checked
+        Ok(ArrayParams {
+            reserved: reader.read_u8()?,
+            var_name: reader.read_u8()?,
+            reserved1: [reader.read_u8()?, reader.read_u8()?],
+        })
+    }
+
+    /// Name of the array variable, with a `$` for character arrays
+    pub fn name(&self) -> String {
+        let letter = basic::letter(self.var_name);
+        if self.var_name & 0x40 != 0 {
+            format!("{}$", letter)
+        } else {
+            letter.to_string()
+        }
+    }
+
+    /// Writes the 4 parameter bytes
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(self.reserved)?;
+        writer.write_u8(self.var_name)?;
+        writer.write_all(&self.reserved1)
+    }
+}
+
+/// Type specific parameters of a header
+#[derive(Debug)]
+pub enum BlockParams {
+    /// Parameters of a program header
+    Program(ProgramParams),
+    /// Parameters of a numeric or character array header
+    Array(ArrayParams),
+    /// Parameters of a code header
+    Bytes(BytesParams),
+    /// Parameters of an unknown header type, kept as they are
+    Unknown([u8; 4]),
+}
+
+impl BlockParams {
+    /// Writes the 4 parameter bytes
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            BlockParams::Program(params) => params.write_to(writer),
+            BlockParams::Array(params) => params.write_to(writer),
+            BlockParams::Bytes(params) => params.write_to(writer),
+            BlockParams::Unknown(params) => writer.write_all(params),
+        }
+    }
+}
+
+/// The 17 byte payload of a header block, describing the data block that
+/// follows it
+#[derive(Debug)]
+pub struct Header {
+    /// Kind of file the header describes
+    pub header_type: HeaderTypeEnum,
+    /// Name padded with spaces
+    pub filename: [u8; 10],
+    /// Length of the data block payload
+    pub len_data: u16,
+    /// Parameters that depend on the header type
+    pub params: BlockParams,
+}
+
+impl Header {
+    /// Reads the 17 bytes of a header block payload
+    pub fn from_bytes<R: Read>(reader: &mut R) -> Result<Header, Error> {
+        let header_type = HeaderTypeEnum::from_u8(reader.read_u8()?);
+
+        let mut filename = [0; 10];
+        reader.read_exact(&mut filename)?;
+
+        let len_data = reader.read_u16::<LittleEndian>()?;
+
+        let params = match header_type {
+            HeaderTypeEnum::Program => BlockParams::Program(ProgramParams::from_bytes(reader)?),
+            HeaderTypeEnum::NumArray | HeaderTypeEnum::CharArray => {
+                BlockParams::Array(ArrayParams::from_bytes(reader)?)
+            }
+            HeaderTypeEnum::Bytes => BlockParams::Bytes(BytesParams::from_bytes(reader)?),
+            HeaderTypeEnum::Unknown(_) => {
+                let mut params = [0; 4];
+                reader.read_exact(&mut params)?;
+                BlockParams::Unknown(params)
+            }
+        };
+
+        Ok(Header {
+            header_type,
+            filename,
+            len_data,
+            params,
+        })
+    }
+
+    /// Header of a block of code loaded to `start_address`
+    pub fn code(filename: &str, start_address: u16, len_data: u16) -> Self {
+        Header {
+            header_type: HeaderTypeEnum::Bytes,
+            filename: filename_bytes(filename),
+            len_data,
+            params: BlockParams::Bytes(BytesParams {
+                start_address,
+                reserved: NO_AUTOSTART.to_le_bytes(),
+            }),
+        }
+    }
+
+    /// The file name as text, without the padding
+    pub fn name(&self) -> String {
+        basic::to_text(&self.filename).trim_end().to_string()
+    }
+
+    /// Writes the 17 header bytes
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(self.header_type.to_u8())?;
+        writer.write_all(&self.filename)?;
+        writer.write_u16::<LittleEndian>(self.len_data)?;
+        self.params.write_to(writer)
+    }
+
+    /// The 17 header bytes between the flag and the checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        // Writing to a Vec can't fail
+        self.write_to(&mut bytes).unwrap();
+        bytes
+    }
+}
+
+/// A file name as stored in headers, cut to 10 characters and padded with
+/// spaces. Characters outside ASCII are replaced by `?`.
+pub fn filename_bytes(name: &str) -> [u8; 10] {
+    let mut filename = [b' '; 10];
+    for (byte, c) in filename.iter_mut().zip(name.chars()) {
+        *byte = if c.is_ascii() { c as u8 } else { b'?' };
+    }
+    filename
+}
+
+/// XOR of all bytes, the checksum the ROM stores after the payload
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+/// Outcome of checking the checksum of one tape block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChecksumStatus {
+    /// Checksum computed from the flag and payload
+    pub expected: u8,
+    /// Checksum stored at the end of the block
+    pub actual: u8,
+}
+
+impl ChecksumStatus {
+    /// Checks a tape block given as flag, payload and checksum
+    pub fn of(data: &[u8]) -> Self {
+        match data.split_last() {
+            Some((&actual, contents)) => ChecksumStatus {
+                expected: checksum(contents),
+                actual,
+            },
+            None => ChecksumStatus {
+                expected: 0,
+                actual: 0,
+            },
+        }
+    }
+
+    /// Whether the stored checksum matches the contents
+    pub fn is_ok(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+/// One length prefixed block of a TAP file: flag, payload and checksum
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Flag byte at the start of the block
+    pub flag: FlagEnum,
+    /// Bytes between the flag and the checksum
+    pub payload: Vec<u8>,
+    /// Checksum as stored, which may not match the contents
+    pub checksum: u8,
+    /// Whether the block is a chunk too short to hold a flag and a checksum.
+    /// It is kept whole in `payload`, and the flag and checksum are its
+    /// byte, or 0 if it has none.
+    pub raw: bool,
+}
+
+impl Block {
+    /// Creates a block with a correct checksum
+    pub fn new(flag: FlagEnum, payload: Vec<u8>) -> Self {
+        let checksum = checksum(&payload) ^ flag.to_u8();
+        Block {
+            flag,
+            payload,
+            checksum,
+            raw: false,
+        }
+    }
+
+    /// Header block holding `header`, with a correct checksum
+    pub fn from_header(header: &Header) -> Self {
+        Block::new(FlagEnum::Header, header.to_bytes())
+    }
+
+    /// Reads one block including its length prefix
+    pub fn from_bytes<R: Read>(reader: &mut R) -> Result<Block, Error> {
+        let len_block = reader.read_u16::<LittleEndian>()?;
+        let mut data = vec![0; len_block as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Block::from_tape_data(&data))
+    }
+
+    /// Builds a block from its flag, payload and checksum, without the
+    /// length prefix. Shorter chunks are kept as raw blocks.
+    pub fn from_tape_data(data: &[u8]) -> Block {
+        match data {
+            [flag, payload @ .., checksum] => Block {
+                flag: FlagEnum::from_u8(*flag),
+                payload: payload.to_vec(),
+                checksum: *checksum,
+                raw: false,
+            },
+            _ => Block {
+                flag: FlagEnum::from_u8(data.first().copied().unwrap_or_default()),
+                payload: data.to_vec(),
+                checksum: data.last().copied().unwrap_or_default(),
+                raw: true,
+            },
+        }
+    }
+
+    /// The header stored in this block, if it is a standard header block
+    pub fn header(&self) -> Option<Header> {
+        if self.flag != FlagEnum::Header || self.payload.len() != HEADER_LEN {
+            return None;
+        }
+        Header::from_bytes(&mut self.payload.as_slice()).ok()
+    }
+
+    /// Short name of the block for listings: the name of its flag, or "Raw"
+    pub fn name(&self) -> &'static str {
+        if self.raw {
+            "Raw"
+        } else {
+            self.flag.name()
+        }
+    }
+
+    /// Bytes the block takes up in a TAP file, with its length prefix
+    pub fn file_len(&self) -> usize {
+        let flag_and_checksum = if self.raw { 0 } else { 2 };
+        2 + self.payload.len() + flag_and_checksum
+    }
+
+    /// Flag, payload and the stored checksum, as the block appears on tape
+    pub fn tape_data(&self) -> Vec<u8> {
+        if self.raw {
+            return self.payload.clone();
+        }
+        let mut data = Vec::with_capacity(self.payload.len() + 2);
+        data.push(self.flag.to_u8());
+        data.extend_from_slice(&self.payload);
+        data.push(self.checksum);
+        data
+    }
+
+    /// Writes the block with its length prefix and the stored checksum, so a
+    /// block is written back exactly as it was read
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.raw {
+            writer.write_u16::<LittleEndian>(self.payload.len() as u16)?;
+            return writer.write_all(&self.payload);
+        }
+        let len = u16::try_from(self.payload.len() + 2)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Block too long for a TAP file"))?;
+        writer.write_u16::<LittleEndian>(len)?;
+        writer.write_u8(self.flag.to_u8())?;
+        writer.write_all(&self.payload)?;
+        writer.write_u8(self.checksum)
+    }
+
+    /// Compares the stored checksum with the one computed from the contents
+    pub fn verify(&self) -> ChecksumStatus {
+        ChecksumStatus {
+            expected: checksum(&self.payload) ^ self.flag.to_u8(),
+            actual: self.checksum,
+        }
+    }
+}
+
+/// Header and data block of code saved with `SAVE "name" CODE start,length`
+pub fn code_file(filename: &str, start_address: u16, data: Vec<u8>) -> io::Result<[Block; 2]> {
+    if start_address as usize + data.len() > 0x10000 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} bytes don't fit in memory at address {}",
+                data.len(),
+                start_address
+            ),
+        ));
+    }
+
+    let header = Header::code(filename, start_address, data.len() as u16);
+    Ok([
+        Block::from_header(&header),
+        Block::new(FlagEnum::Data, data),
+    ])
+}
+
+/// A file on tape: a header and the data block it describes. Either part
+/// can be missing, for headerless blocks or a header at the end of the tape.
+#[derive(Debug)]
+pub struct TapeFile<'a> {
+    /// Index of the first block of the file
+    pub index: usize,
+    /// The header block, if the file has one
+    pub header: Option<Header>,
+    /// The data block, if one follows the header
+    pub data: Option<&'a Block>,
+}
+
+/// A parsed TAP file
+#[derive(Debug)]
+pub struct Tape {
+    /// Blocks in the order they are stored
+    pub blocks: Vec<Block>,
+}
+
+impl Tape {
+    /// Reads a TAP file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Tape::from_bytes(&mut reader)
+    }
+
+    /// Parses blocks until the reader is exhausted
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut blocks: Vec<Block> = Vec::new();
+
+        // Only the start of a block may coincide with the end of the input
+        let mut first = [0; 1];
+        while reader.read(&mut first)? == 1 {
+            let block = Block::from_bytes(&mut first.chain(&mut *reader))?;
+            blocks.push(block);
+        }
+
+        Ok(Tape { blocks })
+    }
+
+    /// Parses an in-memory TAP image, splitting it with [`TapChunks`]
+    /// instead of copying it through [`Read`]
+    pub fn from_slice(data: &[u8]) -> io::Result<Self> {
+        let blocks = TapChunks::new(data)
+            .map(|chunk| chunk.map(Block::from_tape_data))
+            .collect::<io::Result<_>>()?;
+        Ok(Tape { blocks })
+    }
+
+    /// Writes the tape in TAP format, recomputing the lengths and keeping the
+    /// stored checksums
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for block in &self.blocks {
+            block.write_to(writer)?;
+        }
+        Ok(())
+    }
+
+    /// The tape in TAP format, as [`Tape::write_to`] writes it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing to a Vec can't fail
+        self.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Writes the tape to a TAP file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Every block of the tape as flag, payload and checksum
+    pub fn tape_blocks(&self) -> Vec<Vec<u8>> {
+        self.blocks.iter().map(Block::tape_data).collect()
+    }
+
+    /// Groups the blocks into files, pairing every header with the data
+    /// block that follows it
+    pub fn files(&self) -> Vec<TapeFile<'_>> {
+        let mut files = Vec::new();
+        let mut index = 0;
+
+        while index < self.blocks.len() {
+            let file = match self.blocks[index].header() {
+                Some(header) => {
+                    let data = self
+                        .blocks
+                        .get(index + 1)
+                        .filter(|block| block.header().is_none());
+                    TapeFile {
+                        index,
+                        header: Some(header),
+                        data,
+                    }
+                }
+                None => TapeFile {
+                    index,
+                    header: None,
+                    data: Some(&self.blocks[index]),
+                },
+            };
+
+            index += 1 + (file.header.is_some() && file.data.is_some()) as usize;
+            files.push(file);
+        }
+
+        files
+    }
+
+    /// Pulses of one block with the ROM timings, followed by its pause
+    pub fn block_pulses(&self, index: usize, options: &PulseOptions) -> Vec<Pulse> {
+        let Some(block) = self.blocks.get(index) else {
+            return Vec::new();
+        };
+
+        let mut pulses = pulse::block_pulses(&block.tape_data(), options);
+        let mut pause = options.pause_after(pulse::DEFAULT_PAUSE);
+        if index + 1 == self.blocks.len() {
+            // End the last half-wave of the tape with an edge so it can be measured
+            pause = pause.max(1);
+        }
+        pulse::pause_pulses(pause, &mut pulses);
+        pulses
+    }
+
+    /// Pulses of the whole tape with the ROM timings
+    pub fn pulses(&self, options: &PulseOptions) -> Vec<Pulse> {
+        (0..self.blocks.len())
+            .flat_map(|index| self.block_pulses(index, options))
+            .collect()
+    }
+}
+
+/// Writes one length prefixed tape block, computing its checksum
+pub fn write_block<W: Write>(writer: &mut W, flag: u8, payload: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(payload.len() + 2)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Block too long for a TAP file"))?;
+    writer.write_u16::<LittleEndian>(len)?;
+    writer.write_u8(flag)?;
+    writer.write_all(payload)?;
+    writer.write_u8(checksum(payload) ^ flag)
+}
+
+/// Writes tape blocks given as flag, payload and checksum with their length
+/// prefixes, keeping the stored checksums as they are
+pub fn write_tape_blocks<W: Write>(writer: &mut W, tape_blocks: &[Vec<u8>]) -> io::Result<()> {
+    for data in tape_blocks {
+        let len = u16::try_from(data.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Block too long for a TAP file"))?;
+        writer.write_u16::<LittleEndian>(len)?;
+        writer.write_all(data)?;
+    }
+    Ok(())
+}
+
+/// Iterator over the blocks of an in-memory TAP image that borrows every
+/// block's flag, payload and checksum instead of copying them. It splits the
+/// image at the same places as [`Tape::from_bytes`] and fails on the same
+/// truncated blocks.
+#[derive(Debug, Clone)]
+pub struct TapChunks<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TapChunks<'a> {
+    /// Iterates over the blocks of a TAP image held in memory
+    pub fn new(data: &'a [u8]) -> Self {
+        TapChunks { data }
+    }
+}
+
+impl<'a> Iterator for TapChunks<'a> {
+    type Item = io::Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let chunk = match self.data {
+            [lo, hi, rest @ ..] => {
+                let len = u16::from_le_bytes([*lo, *hi]) as usize;
+                rest.get(..len).map(|chunk| (chunk, &rest[len..]))
+            }
+            _ => None,
+        };
+
+        match chunk {
+            Some((chunk, rest)) => {
+                self.data = rest;
+                Some(Ok(chunk))
+            }
+            None => {
+                self.data = &[];
+                Some(Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Truncated TAP block",
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A code file holding `data`, the tape most rendering tests start from
+    pub(crate) fn code_tape(data: Vec<u8>) -> Tape {
+        Tape {
+            blocks: code_file("code", 32768, data).unwrap().to_vec(),
+        }
+    }
+
+    #[test]
+    fn custom_flags_and_header_types_are_kept() {
+        for value in [0x00, 0x42, 0xFF] {
+            assert_eq!(FlagEnum::from_u8(value).to_u8(), value);
+        }
+        assert_eq!(FlagEnum::from_u8(0x42), FlagEnum::Custom(0x42));
+
+        let mut payload = vec![7];
+        payload.extend(b"odd name  ");
+        payload.extend([0x10, 0x00, 1, 2, 3, 4]);
+        let block = Block::new(FlagEnum::Header, payload.clone());
+        let header = block.header().unwrap();
+        assert_eq!(header.header_type, HeaderTypeEnum::Unknown(7));
+        assert!(matches!(header.params, BlockParams::Unknown([1, 2, 3, 4])));
+        assert_eq!(header.to_bytes(), payload);
+    }
+
+    #[test]
+    fn checksums_cover_flag_and_payload() {
+        let block = Block::new(FlagEnum::Data, vec![0x12, 0x34]);
+        assert_eq!(block.checksum, 0xFF ^ 0x12 ^ 0x34);
+        assert!(block.verify().is_ok());
+        assert_eq!(ChecksumStatus::of(&block.tape_data()), block.verify());
+
+        let mut data = block.tape_data();
+        data[1] ^= 0x80;
+        let status = ChecksumStatus::of(&data);
+        assert!(!status.is_ok());
+        assert_eq!(
+            (status.actual, status.expected),
+            (block.checksum, block.checksum ^ 0x80)
+        );
+        assert_eq!(Block::from_tape_data(&data).verify(), status);
+    }
+
+    #[test]
+    fn tapes_are_written_back_as_read() {
+        let mut bytes = code_tape(vec![1, 2, 3]).to_bytes();
+        // A wrong checksum on the data block, and a block with a custom flag
+        *bytes.last_mut().unwrap() ^= 0x55;
+        bytes.extend([4, 0, 0x42, 7, 8, 0x99]);
+
+        let tape = Tape::from_slice(&bytes).unwrap();
+        assert!(!tape.blocks[1].verify().is_ok());
+        assert_eq!(tape.to_bytes(), bytes);
+
+        let header = tape.blocks[0].header().unwrap();
+        assert_eq!(header.to_bytes(), tape.blocks[0].payload);
+    }
+
+    #[test]
+    fn files_pair_headers_with_the_following_data() {
+        let mut tape = code_tape(vec![1, 2, 3]);
+        tape.blocks.push(Block::new(FlagEnum::Data, vec![4]));
+        tape.blocks
+            .push(Block::from_header(&Header::code("last", 0, 1)));
+
+        let files = tape.files();
+        let layout: Vec<(usize, Option<String>, Option<Vec<u8>>)> = files
+            .iter()
+            .map(|file| {
+                let name = file.header.as_ref().map(Header::name);
+                (file.index, name, file.data.map(|data| data.payload.clone()))
+            })
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (0, Some("code".to_string()), Some(vec![1, 2, 3])),
+                (2, None, Some(vec![4])),
+                (3, Some("last".to_string()), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn short_chunks_are_kept_as_raw_blocks() {
+        let bytes = [0, 0, 1, 0, 0x42, 2, 0, 0xFF, 0xFF];
+        let tape = Tape::from_slice(&bytes).unwrap();
+        let raw: Vec<bool> = tape.blocks.iter().map(|block| block.raw).collect();
+        assert_eq!(raw, [true, true, false]);
+        assert_eq!(tape.tape_blocks(), [vec![], vec![0x42], vec![0xFF, 0xFF]]);
+        assert_eq!(tape.blocks[1].verify(), ChecksumStatus::of(&[0x42]));
+        assert_eq!(tape.to_bytes(), bytes);
+    }
+
+    /// The pulses of a pause of `milliseconds`
+    pub(crate) fn pause(milliseconds: u32) -> Vec<Pulse> {
+        let mut pulses = Vec::new();
+        pulse::pause_pulses(milliseconds, &mut pulses);
+        pulses
+    }
+
+    #[test]
+    fn pauses_follow_headers_and_data() {
+        let tape = code_tape(vec![1, 2, 3]);
+        let shorter = PulseOptions {
+            pause: Some(250),
+            ..PulseOptions::default()
+        };
+        for (options, milliseconds) in [
+            (PulseOptions::default(), pulse::DEFAULT_PAUSE),
+            (shorter, 250),
+        ] {
+            for index in 0..tape.blocks.len() {
+                assert!(tape
+                    .block_pulses(index, &options)
+                    .ends_with(&pause(milliseconds)));
+            }
+        }
+
+        // Without pauses only the end of the tape gets an edge
+        let none = PulseOptions {
+            pause: Some(0),
+            ..PulseOptions::default()
+        };
+        assert!(!tape.block_pulses(0, &none).ends_with(&pause(1)));
+        assert!(tape.block_pulses(1, &none).ends_with(&pause(1)));
+    }
+
+    #[test]
+    fn slices_and_readers_split_tapes_alike() {
+        let mut tape = code_tape(vec![1, 2, 3]);
+        tape.blocks
+            .push(Block::new(FlagEnum::from_u8(0x42), vec![4, 5]));
+        let bytes = tape.to_bytes();
+
+        let chunks: Vec<&[u8]> = TapChunks::new(&bytes).map(Result::unwrap).collect();
+        assert_eq!(chunks, tape.tape_blocks());
+
+        // Every cut either splits both ways into the same blocks or fails both ways
+        for len in 0..=bytes.len() {
+            let cut = &bytes[..len];
+            match (Tape::from_slice(cut), Tape::from_bytes(&mut &cut[..])) {
+                (Ok(slice), Ok(reader)) => assert_eq!(slice.tape_blocks(), reader.tape_blocks()),
+                (Err(slice), Err(reader)) => assert_eq!(slice.kind(), reader.kind()),
+                (slice, reader) => panic!("{} bytes: {:?} vs {:?}", len, slice, reader),
+            }
+        }
+    }
+}
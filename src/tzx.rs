@@ -0,0 +1,967 @@
+//! TZX files, parsed into blocks and rendered to pulses
+
+use crate::csw;
+use crate::pulse::{self, Pulse, PulseOptions, Timings};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{self, Error, ErrorKind, Read};
+
+/// Bytes every TZX file starts with
+pub const SIGNATURE: &[u8; 8] = b"ZXTape!\x1A";
+
+/// Guards against jump and loop blocks that never reach the end of the tape
+const MAX_PLAYBACK_STEPS: usize = 1_000_000;
+
+/// Pulse pattern of a generalized data block
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    /// Bits 0-1 set the level before the first pulse: toggle, keep, low or high
+    pub flags: u8,
+    /// Pulse lengths in T-states, a zero ends the symbol early
+    pub pulses: Vec<u16>,
+}
+
+impl Symbol {
+    fn from_bytes<R: Read>(reader: &mut R, max_pulses: u8) -> io::Result<Self> {
+        let flags = reader.read_u8()?;
+        let mut pulses = Vec::with_capacity(max_pulses as usize);
+        for _ in 0..max_pulses {
+            pulses.push(reader.read_u16::<LittleEndian>()?);
+        }
+        Ok(Symbol { flags, pulses })
+    }
+
+    /// Bits 0-1 of the flags say what happens to the level before the first
+    /// pulse; a zero-length pulse ends the symbol early
+    fn append_pulses(&self, pulses: &mut Vec<Pulse>, options: &PulseOptions) {
+        for (index, &length) in self.pulses.iter().take_while(|&&p| p != 0).enumerate() {
+            let length = options.duration(length as u32);
+            pulses.push(match (index, self.flags & 0x03) {
+                (0, 1) => Pulse::Hold(length),
+                (0, 2) => Pulse::Level(false, length),
+                (0, 3) => Pulse::Level(true, length),
+                _ => Pulse::Edge(length),
+            });
+        }
+    }
+}
+
+/// Contents of a generalized data block (ID 0x19)
+#[derive(Debug, Clone)]
+pub struct GeneralizedData {
+    /// Pause after the block in milliseconds
+    pub pause: u16,
+    /// Symbols the pilot and sync are made of
+    pub pilot_symbols: Vec<Symbol>,
+    /// (symbol index, repetitions) pairs
+    pub pilot_stream: Vec<(u8, u16)>,
+    /// Symbols the data is made of
+    pub data_symbols: Vec<Symbol>,
+    /// Number of symbols stored in `data`
+    pub data_symbol_count: u32,
+    /// Symbol indices packed most significant bit first
+    pub data: Vec<u8>,
+}
+
+impl GeneralizedData {
+    fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let pause = reader.read_u16::<LittleEndian>()?;
+        let pilot_count = reader.read_u32::<LittleEndian>()?;
+        let pilot_max_pulses = reader.read_u8()?;
+        let pilot_alphabet = alphabet_size(reader.read_u8()?);
+        let data_symbol_count = reader.read_u32::<LittleEndian>()?;
+        let data_max_pulses = reader.read_u8()?;
+        let data_alphabet = alphabet_size(reader.read_u8()?);
+
+        let mut pilot_symbols = Vec::new();
+        let mut pilot_stream = Vec::new();
+        if pilot_count > 0 {
+            for _ in 0..pilot_alphabet {
+                pilot_symbols.push(Symbol::from_bytes(reader, pilot_max_pulses)?);
+            }
+            for _ in 0..pilot_count {
+                pilot_stream.push((reader.read_u8()?, reader.read_u16::<LittleEndian>()?));
+            }
+        }
+
+        let mut data_symbols = Vec::new();
+        let mut data = Vec::new();
+        if data_symbol_count > 0 {
+            for _ in 0..data_alphabet {
+                data_symbols.push(Symbol::from_bytes(reader, data_max_pulses)?);
+            }
+            let bits = bits_per_symbol(data_symbols.len()) as u64;
+            let len = (bits * data_symbol_count as u64).div_ceil(8);
+            data = read_vec(reader, len as usize)?;
+        }
+
+        Ok(GeneralizedData {
+            pause,
+            pilot_symbols,
+            pilot_stream,
+            data_symbols,
+            data_symbol_count,
+            data,
+        })
+    }
+
+    fn append_pulses(&self, pulses: &mut Vec<Pulse>, options: &PulseOptions) -> io::Result<()> {
+        for &(symbol, repetitions) in &self.pilot_stream {
+            let symbol = self
+                .pilot_symbols
+                .get(symbol as usize)
+                .ok_or(Error::new(ErrorKind::InvalidData, "Invalid pilot symbol"))?;
+            for _ in 0..options.pilot_pulses(repetitions as usize) {
+                symbol.append_pulses(pulses, options);
+            }
+        }
+
+        // Symbols are packed most significant bit first
+        let bits = bits_per_symbol(self.data_symbols.len());
+        let mut position = 0;
+        for _ in 0..self.data_symbol_count {
+            let mut symbol = 0;
+            for _ in 0..bits {
+                let bit = (self.data[position / 8] >> (7 - position % 8)) & 1;
+                symbol = symbol << 1 | bit as usize;
+                position += 1;
+            }
+            self.data_symbols
+                .get(symbol)
+                .ok_or(Error::new(ErrorKind::InvalidData, "Invalid data symbol"))?
+                .append_pulses(pulses, options);
+        }
+
+        pulse::pause_pulses(options.pause_after(self.pause as u32), pulses);
+        Ok(())
+    }
+}
+
+fn alphabet_size(value: u8) -> usize {
+    if value == 0 {
+        256
+    } else {
+        value as usize
+    }
+}
+
+fn bits_per_symbol(alphabet_size: usize) -> u32 {
+    alphabet_size.next_power_of_two().trailing_zeros()
+}
+
+/// A block of a TZX file, named after the specification
+#[derive(Debug, Clone)]
+pub enum TzxBlock {
+    /// ID 0x10, a block with the timings of the ROM loader
+    StandardSpeed {
+        /// Pause after the block in milliseconds
+        pause: u16,
+        /// Flag, payload and checksum as in a TAP block
+        data: Vec<u8>,
+    },
+    /// ID 0x11, a block with its own timings
+    TurboSpeed {
+        /// Pulse lengths of the pilot, sync and data
+        timings: Timings,
+        /// Bits used in the last byte, from the most significant
+        used_bits: u8,
+        /// Pause after the block in milliseconds
+        pause: u16,
+        /// Flag, payload and checksum as in a TAP block
+        data: Vec<u8>,
+    },
+    /// ID 0x12, `count` pulses of the same length
+    PureTone {
+        /// Length of each pulse in T-states
+        pulse: u16,
+        /// Number of pulses
+        count: u16,
+    },
+    /// ID 0x13, pulses of the given lengths in T-states
+    PulseSequence(Vec<u16>),
+    /// ID 0x14, data without pilot or sync
+    PureData {
+        /// Length of a zero bit pulse in T-states
+        zero_pulse: u16,
+        /// Length of a one bit pulse in T-states
+        one_pulse: u16,
+        /// Bits used in the last byte, from the most significant
+        used_bits: u8,
+        /// Pause after the block in milliseconds
+        pause: u16,
+        /// Bytes sent most significant bit first
+        data: Vec<u8>,
+    },
+    /// ID 0x15, the level sampled at a fixed rate
+    DirectRecording {
+        /// Length of each sample in T-states
+        tstates_per_sample: u16,
+        /// Pause after the block in milliseconds
+        pause: u16,
+        /// Samples used in the last byte, from the most significant
+        used_bits: u8,
+        /// One bit per sample, most significant first
+        data: Vec<u8>,
+    },
+    /// ID 0x18, a CSW recording
+    CswRecording {
+        /// Pause after the block in milliseconds
+        pause: u16,
+        /// Samples per second of the recording
+        sample_rate: u32,
+        /// 1 for RLE, 2 for Z-RLE
+        compression: u8,
+        /// Number of pulses once decompressed
+        pulse_count: u32,
+        /// Compressed runs of the recording
+        data: Vec<u8>,
+    },
+    /// ID 0x19, pulses described by a table of symbols
+    GeneralizedData(GeneralizedData),
+    /// ID 0x20, a pause of 0 means "stop the tape"
+    Pause(u16),
+    /// ID 0x21, start of a named group of blocks
+    GroupStart(String),
+    /// ID 0x22, end of a group
+    GroupEnd,
+    /// ID 0x23, jump by the given number of blocks
+    Jump(i16),
+    /// ID 0x24, repeat the blocks up to the loop end this many times
+    LoopStart(u16),
+    /// ID 0x25, end of a loop
+    LoopEnd,
+    /// ID 0x26, play the blocks at these relative offsets, then carry on
+    CallSequence(Vec<i16>),
+    /// ID 0x27, return from a call sequence
+    Return,
+    /// ID 0x28, a menu of relative offsets and descriptions
+    Select(Vec<(i16, String)>),
+    /// ID 0x2A, stop the tape on a 48K machine
+    StopIf48K,
+    /// ID 0x2B, the level the next block starts from
+    SetSignalLevel(bool),
+    /// ID 0x30, a description of the following blocks
+    Text(String),
+    /// ID 0x31, a message shown while playing
+    Message {
+        /// Seconds to show the message for
+        time: u8,
+        /// Text of the message
+        text: String,
+    },
+    /// ID 0x32, (kind, text) pairs such as title and author
+    ArchiveInfo(Vec<(u8, String)>),
+    /// ID 0x33, (type, ID, compatibility) triples
+    HardwareType(Vec<[u8; 3]>),
+    /// ID 0x35, data for a custom application
+    CustomInfo {
+        /// Identification string of the application
+        id: String,
+        /// Custom data
+        data: Vec<u8>,
+    },
+    /// ID 0x5A, marks where two TZX files were joined
+    Glue,
+    /// Deprecated and unknown blocks are kept as raw bytes
+    Unknown {
+        /// ID of the block
+        id: u8,
+        /// Bytes of the block after its length
+        data: Vec<u8>,
+    },
+}
+
+impl TzxBlock {
+    fn from_bytes<R: Read>(id: u8, reader: &mut R) -> io::Result<Self> {
+        let block = match id {
+            0x10 => {
+                let pause = reader.read_u16::<LittleEndian>()?;
+                let len = reader.read_u16::<LittleEndian>()?;
+                TzxBlock::StandardSpeed {
+                    pause,
+                    data: read_vec(reader, len as usize)?,
+                }
+            }
+            0x11 => {
+                let pilot_pulse = reader.read_u16::<LittleEndian>()? as u32;
+                let sync1_pulse = reader.read_u16::<LittleEndian>()? as u32;
+                let sync2_pulse = reader.read_u16::<LittleEndian>()? as u32;
+                let zero_pulse = reader.read_u16::<LittleEndian>()? as u32;
+                let one_pulse = reader.read_u16::<LittleEndian>()? as u32;
+                let pilot_pulses = reader.read_u16::<LittleEndian>()? as usize;
+                let used_bits = reader.read_u8()?;
+                let pause = reader.read_u16::<LittleEndian>()?;
+                let len = reader.read_u24::<LittleEndian>()?;
+                TzxBlock::TurboSpeed {
+                    timings: Timings {
+                        pilot_pulse,
+                        pilot_pulses,
+                        sync1_pulse,
+                        sync2_pulse,
+                        zero_pulse,
+                        one_pulse,
+                    },
+                    used_bits,
+                    pause,
+                    data: read_vec(reader, len as usize)?,
+                }
+            }
+            0x12 => TzxBlock::PureTone {
+                pulse: reader.read_u16::<LittleEndian>()?,
+                count: reader.read_u16::<LittleEndian>()?,
+            },
+            0x13 => {
+                let count = reader.read_u8()?;
+                let mut pulses = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    pulses.push(reader.read_u16::<LittleEndian>()?);
+                }
+                TzxBlock::PulseSequence(pulses)
+            }
+            0x14 => {
+                let zero_pulse = reader.read_u16::<LittleEndian>()?;
+                let one_pulse = reader.read_u16::<LittleEndian>()?;
+                let used_bits = reader.read_u8()?;
+                let pause = reader.read_u16::<LittleEndian>()?;
+                let len = reader.read_u24::<LittleEndian>()?;
+                TzxBlock::PureData {
+                    zero_pulse,
+                    one_pulse,
+                    used_bits,
+                    pause,
+                    data: read_vec(reader, len as usize)?,
+                }
+            }
+            0x15 => {
+                let tstates_per_sample = reader.read_u16::<LittleEndian>()?;
+                let pause = reader.read_u16::<LittleEndian>()?;
+                let used_bits = reader.read_u8()?;
+                let len = reader.read_u24::<LittleEndian>()?;
+                TzxBlock::DirectRecording {
+                    tstates_per_sample,
+                    pause,
+                    used_bits,
+                    data: read_vec(reader, len as usize)?,
+                }
+            }
+            0x18 => {
+                let len = reader.read_u32::<LittleEndian>()?;
+                let pause = reader.read_u16::<LittleEndian>()?;
+                let sample_rate = reader.read_u24::<LittleEndian>()?;
+                let compression = reader.read_u8()?;
+                let pulse_count = reader.read_u32::<LittleEndian>()?;
+                let data_len = len.checked_sub(10).ok_or(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid CSW recording length",
+                ))?;
+                TzxBlock::CswRecording {
+                    pause,
+                    sample_rate,
+                    compression,
+                    pulse_count,
+                    data: read_vec(reader, data_len as usize)?,
+                }
+            }
+            0x19 => {
+                // Parse from the exact block length so a bad table can't
+                // desynchronise the rest of the file
+                let len = reader.read_u32::<LittleEndian>()?;
+                let block = read_vec(reader, len as usize)?;
+                TzxBlock::GeneralizedData(GeneralizedData::from_bytes(&mut block.as_slice())?)
+            }
+            0x20 => TzxBlock::Pause(reader.read_u16::<LittleEndian>()?),
+            0x21 => {
+                let len = reader.read_u8()?;
+                TzxBlock::GroupStart(read_string(reader, len as usize)?)
+            }
+            0x22 => TzxBlock::GroupEnd,
+            0x23 => TzxBlock::Jump(reader.read_i16::<LittleEndian>()?),
+            0x24 => TzxBlock::LoopStart(reader.read_u16::<LittleEndian>()?),
+            0x25 => TzxBlock::LoopEnd,
+            0x26 => {
+                let count = reader.read_u16::<LittleEndian>()?;
+                let mut offsets = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    offsets.push(reader.read_i16::<LittleEndian>()?);
+                }
+                TzxBlock::CallSequence(offsets)
+            }
+            0x27 => TzxBlock::Return,
+            0x28 => {
+                let _len = reader.read_u16::<LittleEndian>()?;
+                let count = reader.read_u8()?;
+                let mut selections = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let offset = reader.read_i16::<LittleEndian>()?;
+                    let len = reader.read_u8()?;
+                    selections.push((offset, read_string(reader, len as usize)?));
+                }
+                TzxBlock::Select(selections)
+            }
+            0x2A => {
+                let len = reader.read_u32::<LittleEndian>()?;
+                read_vec(reader, len as usize)?;
+                TzxBlock::StopIf48K
+            }
+            0x2B => {
+                let len = reader.read_u32::<LittleEndian>()?;
+                let data = read_vec(reader, len as usize)?;
+                TzxBlock::SetSignalLevel(data.first().is_some_and(|&level| level != 0))
+            }
+            0x30 => {
+                let len = reader.read_u8()?;
+                TzxBlock::Text(read_string(reader, len as usize)?)
+            }
+            0x31 => {
+                let time = reader.read_u8()?;
+                let len = reader.read_u8()?;
+                TzxBlock::Message {
+                    time,
+                    text: read_string(reader, len as usize)?,
+                }
+            }
+            0x32 => {
+                let _len = reader.read_u16::<LittleEndian>()?;
+                let count = reader.read_u8()?;
+                let mut entries = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let id = reader.read_u8()?;
+                    let len = reader.read_u8()?;
+                    entries.push((id, read_string(reader, len as usize)?));
+                }
+                TzxBlock::ArchiveInfo(entries)
+            }
+            0x33 => {
+                let count = reader.read_u8()?;
+                let mut machines = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let mut machine = [0; 3];
+                    reader.read_exact(&mut machine)?;
+                    machines.push(machine);
+                }
+                TzxBlock::HardwareType(machines)
+            }
+            0x35 => {
+                let id = read_string(reader, 10)?;
+                let len = reader.read_u32::<LittleEndian>()?;
+                TzxBlock::CustomInfo {
+                    id,
+                    data: read_vec(reader, len as usize)?,
+                }
+            }
+            0x5A => {
+                read_vec(reader, 9)?;
+                TzxBlock::Glue
+            }
+            // Emulation info (deprecated) has a fixed size
+            0x34 => TzxBlock::Unknown {
+                id,
+                data: read_vec(reader, 8)?,
+            },
+            // Snapshot (deprecated): type byte followed by a 24 bit length
+            0x40 => {
+                let snapshot_type = reader.read_u8()?;
+                let len = reader.read_u24::<LittleEndian>()?;
+                let mut data = vec![snapshot_type];
+                data.extend_from_slice(&len.to_le_bytes()[..3]);
+                data.extend(read_vec(reader, len as usize)?);
+                TzxBlock::Unknown { id, data }
+            }
+            // Everything else, including the C64 blocks, starts with a 32 bit length
+            _ => {
+                let len = reader.read_u32::<LittleEndian>()?;
+                TzxBlock::Unknown {
+                    id,
+                    data: read_vec(reader, len as usize)?,
+                }
+            }
+        };
+
+        Ok(block)
+    }
+
+    /// Appends the signal of the block, including the pause after it. Blocks
+    /// that only steer playback add nothing. A pause override in `options`
+    /// applies to data blocks, pause blocks are played as stored.
+    pub fn append_pulses(&self, pulses: &mut Vec<Pulse>, options: &PulseOptions) -> io::Result<()> {
+        match self {
+            TzxBlock::StandardSpeed { pause, data } => {
+                let timings = options.timings(&Timings::rom(data.first().copied().unwrap_or(0xFF)));
+                pulse::timed_block_pulses(&timings, data, 8, pulses);
+                pulse::pause_pulses(options.pause_after(*pause as u32), pulses);
+            }
+            TzxBlock::TurboSpeed {
+                timings,
+                used_bits,
+                pause,
+                data,
+            } => {
+                pulse::timed_block_pulses(&options.timings(timings), data, *used_bits, pulses);
+                pulse::pause_pulses(options.pause_after(*pause as u32), pulses);
+            }
+            TzxBlock::PureTone { pulse, count } => {
+                pulses.extend(std::iter::repeat_n(
+                    Pulse::Edge(options.duration(*pulse as u32)),
+                    options.pilot_pulses(*count as usize),
+                ));
+            }
+            TzxBlock::PulseSequence(lengths) => {
+                pulses.extend(
+                    lengths
+                        .iter()
+                        .map(|&length| Pulse::Edge(options.duration(length as u32))),
+                );
+            }
+            TzxBlock::PureData {
+                zero_pulse,
+                one_pulse,
+                used_bits,
+                pause,
+                data,
+            } => {
+                pulse::data_pulses(
+                    options.duration(*zero_pulse as u32),
+                    options.duration(*one_pulse as u32),
+                    data,
+                    *used_bits,
+                    pulses,
+                );
+                pulse::pause_pulses(options.pause_after(*pause as u32), pulses);
+            }
+            TzxBlock::DirectRecording {
+                tstates_per_sample,
+                pause,
+                used_bits,
+                data,
+            } => {
+                // Each bit is one sample, 1 for high and 0 for low
+                for (index, &byte) in data.iter().enumerate() {
+                    let bits = if index + 1 == data.len() {
+                        (*used_bits).clamp(1, 8)
+                    } else {
+                        8
+                    };
+                    for i in (8 - bits..8).rev() {
+                        pulses.push(Pulse::Level(
+                            (byte >> i) & 1 == 1,
+                            options.duration(*tstates_per_sample as u32),
+                        ));
+                    }
+                }
+                pulse::pause_pulses(options.pause_after(*pause as u32), pulses);
+            }
+            TzxBlock::CswRecording {
+                pause,
+                sample_rate,
+                compression,
+                data,
+                ..
+            } => {
+                let runs = csw::decode_runs(*compression, data)?;
+                csw::runs_to_pulses(&runs, *sample_rate, options, pulses);
+                pulse::pause_pulses(options.pause_after(*pause as u32), pulses);
+            }
+            TzxBlock::GeneralizedData(block) => block.append_pulses(pulses, options)?,
+            TzxBlock::Pause(pause) => pulse::pause_pulses(*pause as u32, pulses),
+            TzxBlock::SetSignalLevel(level) => pulses.push(Pulse::Level(*level, 0)),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// The ID the block is stored with
+    pub fn id(&self) -> u8 {
+        match self {
+            TzxBlock::StandardSpeed { .. } => 0x10,
+            TzxBlock::TurboSpeed { .. } => 0x11,
+            TzxBlock::PureTone { .. } => 0x12,
+            TzxBlock::PulseSequence(_) => 0x13,
+            TzxBlock::PureData { .. } => 0x14,
+            TzxBlock::DirectRecording { .. } => 0x15,
+            TzxBlock::CswRecording { .. } => 0x18,
+            TzxBlock::GeneralizedData(_) => 0x19,
+            TzxBlock::Pause(_) => 0x20,
+            TzxBlock::GroupStart(_) => 0x21,
+            TzxBlock::GroupEnd => 0x22,
+            TzxBlock::Jump(_) => 0x23,
+            TzxBlock::LoopStart(_) => 0x24,
+            TzxBlock::LoopEnd => 0x25,
+            TzxBlock::CallSequence(_) => 0x26,
+            TzxBlock::Return => 0x27,
+            TzxBlock::Select(_) => 0x28,
+            TzxBlock::StopIf48K => 0x2A,
+            TzxBlock::SetSignalLevel(_) => 0x2B,
+            TzxBlock::Text(_) => 0x30,
+            TzxBlock::Message { .. } => 0x31,
+            TzxBlock::ArchiveInfo(_) => 0x32,
+            TzxBlock::HardwareType(_) => 0x33,
+            TzxBlock::CustomInfo { .. } => 0x35,
+            TzxBlock::Glue => 0x5A,
+            TzxBlock::Unknown { id, .. } => *id,
+        }
+    }
+
+    /// Whether the tape stops after the block until it is started again: a
+    /// pause of 0, or the 48K stop since playback behaves like a 48K machine
+    pub fn stops_tape(&self) -> bool {
+        matches!(self, TzxBlock::Pause(0) | TzxBlock::StopIf48K)
+    }
+
+    /// One line summary of the block for listings
+    pub fn describe(&self) -> String {
+        match self {
+            TzxBlock::StandardSpeed { pause, data } => {
+                format!(
+                    "Standard speed data, {} bytes, pause {} ms",
+                    data.len(),
+                    pause
+                )
+            }
+            TzxBlock::TurboSpeed {
+                timings,
+                pause,
+                data,
+                ..
+            } => format!(
+                "Turbo speed data, {} bytes, pilot {}x{} T, bits {}/{} T, pause {} ms",
+                data.len(),
+                timings.pilot_pulses,
+                timings.pilot_pulse,
+                timings.zero_pulse,
+                timings.one_pulse,
+                pause
+            ),
+            TzxBlock::PureTone { pulse, count } => {
+                format!("Pure tone, {} pulses of {} T", count, pulse)
+            }
+            TzxBlock::PulseSequence(lengths) => format!("Pulse sequence, {} pulses", lengths.len()),
+            TzxBlock::PureData { pause, data, .. } => {
+                format!("Pure data, {} bytes, pause {} ms", data.len(), pause)
+            }
+            TzxBlock::DirectRecording {
+                tstates_per_sample,
+                pause,
+                data,
+                ..
+            } => format!(
+                "Direct recording, {} bytes at {} T per sample, pause {} ms",
+                data.len(),
+                tstates_per_sample,
+                pause
+            ),
+            TzxBlock::CswRecording {
+                sample_rate,
+                compression,
+                pulse_count,
+                ..
+            } => format!(
+                "CSW recording, {} pulses at {} Hz, compression {}",
+                pulse_count, sample_rate, compression
+            ),
+            TzxBlock::GeneralizedData(block) => format!(
+                "Generalized data, {} symbols, pause {} ms",
+                block.data_symbol_count, block.pause
+            ),
+            TzxBlock::Pause(0) => "Stop the tape".to_string(),
+            TzxBlock::Pause(pause) => format!("Pause {} ms", pause),
+            TzxBlock::GroupStart(name) => format!("Group start: {}", name),
+            TzxBlock::GroupEnd => "Group end".to_string(),
+            TzxBlock::Jump(offset) => format!("Jump {:+}", offset),
+            TzxBlock::LoopStart(repetitions) => format!("Loop start, {} repetitions", repetitions),
+            TzxBlock::LoopEnd => "Loop end".to_string(),
+            TzxBlock::CallSequence(offsets) => format!("Call sequence {:?}", offsets),
+            TzxBlock::Return => "Return from sequence".to_string(),
+            TzxBlock::Select(selections) => {
+                let options: Vec<String> = selections
+                    .iter()
+                    .map(|(offset, text)| format!("{} ({:+})", text, offset))
+                    .collect();
+                format!("Select: {}", options.join(", "))
+            }
+            TzxBlock::StopIf48K => "Stop the tape if in 48K mode".to_string(),
+            TzxBlock::SetSignalLevel(level) => {
+                format!("Set signal level {}", if *level { "high" } else { "low" })
+            }
+            TzxBlock::Text(text) => format!("Text: {}", text),
+            TzxBlock::Message { time, text } => format!("Message ({} s): {}", time, text),
+            TzxBlock::ArchiveInfo(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(id, text)| format!("{:#04x}={}", id, text))
+                    .collect();
+                format!("Archive info: {}", entries.join(", "))
+            }
+            TzxBlock::HardwareType(machines) => {
+                format!("Hardware type, {} entries: {:?}", machines.len(), machines)
+            }
+            TzxBlock::CustomInfo { id, data } => {
+                format!("Custom info '{}', {} bytes", id.trim_end(), data.len())
+            }
+            TzxBlock::Glue => "Glue".to_string(),
+            TzxBlock::Unknown { id, data } => {
+                format!("Unsupported block {:#04x}, {} bytes", id, data.len())
+            }
+        }
+    }
+
+    /// The block as a standard ROM block (flag, payload and checksum), if it
+    /// carries whole bytes in that format
+    pub fn tap_data(&self) -> Option<&[u8]> {
+        match self {
+            TzxBlock::StandardSpeed { data, .. } => Some(data),
+            TzxBlock::TurboSpeed {
+                used_bits: 8, data, ..
+            } => Some(data),
+            TzxBlock::PureData {
+                used_bits: 8, data, ..
+            } => Some(data),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed TZX file
+#[derive(Debug)]
+pub struct Tzx {
+    /// Major version of the format
+    pub major: u8,
+    /// Minor version of the format
+    pub minor: u8,
+    /// Blocks in the order they are stored
+    pub blocks: Vec<TzxBlock>,
+}
+
+impl Tzx {
+    /// Reads a TZX file, checking the signature
+    pub fn from_bytes<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut signature = [0; 8];
+        reader.read_exact(&mut signature)?;
+        if &signature != SIGNATURE {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a TZX file"));
+        }
+
+        let major = reader.read_u8()?;
+        let minor = reader.read_u8()?;
+
+        let mut blocks = Vec::new();
+        let mut id = [0; 1];
+        while reader.read(&mut id)? == 1 {
+            blocks.push(TzxBlock::from_bytes(id[0], reader)?);
+        }
+
+        Ok(Tzx {
+            major,
+            minor,
+            blocks,
+        })
+    }
+
+    /// Indices of the blocks in the order a tape deck would play them, after
+    /// following jumps, loops and call sequences. Select blocks continue with
+    /// the next block, and blocks that stop the tape stay in the order for
+    /// the player to stop at.
+    pub fn playback_order(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut index = 0;
+        let mut loop_state: Option<(usize, u16)> = None;
+        let mut call_state: Option<(usize, usize)> = None;
+
+        for _ in 0..MAX_PLAYBACK_STEPS {
+            let Some(block) = self.blocks.get(index) else {
+                break;
+            };
+
+            let next = match block {
+                TzxBlock::Jump(offset) => relative_index(index, *offset),
+                TzxBlock::LoopStart(repetitions) => {
+                    loop_state = Some((index + 1, *repetitions));
+                    Some(index + 1)
+                }
+                TzxBlock::LoopEnd => match loop_state {
+                    Some((start, repetitions)) if repetitions > 1 => {
+                        loop_state = Some((start, repetitions - 1));
+                        Some(start)
+                    }
+                    _ => {
+                        loop_state = None;
+                        Some(index + 1)
+                    }
+                },
+                TzxBlock::CallSequence(offsets) => match offsets.first() {
+                    Some(&offset) => {
+                        call_state = Some((index, 0));
+                        relative_index(index, offset)
+                    }
+                    None => Some(index + 1),
+                },
+                TzxBlock::Return => match call_state {
+                    Some((call_index, position)) => {
+                        let offsets = match &self.blocks[call_index] {
+                            TzxBlock::CallSequence(offsets) => offsets.as_slice(),
+                            _ => &[],
+                        };
+                        match offsets.get(position + 1) {
+                            Some(&offset) => {
+                                call_state = Some((call_index, position + 1));
+                                relative_index(call_index, offset)
+                            }
+                            None => {
+                                call_state = None;
+                                Some(call_index + 1)
+                            }
+                        }
+                    }
+                    None => Some(index + 1),
+                },
+                _ => {
+                    order.push(index);
+                    Some(index + 1)
+                }
+            };
+
+            match next {
+                Some(next) => index = next,
+                None => break,
+            }
+        }
+
+        order
+    }
+
+    /// Pulses of the blocks in playback order, following jumps, loops and calls
+    pub fn pulses(&self, options: &PulseOptions) -> io::Result<Vec<Pulse>> {
+        let mut pulses = Vec::new();
+        for index in self.playback_order() {
+            self.blocks[index].append_pulses(&mut pulses, options)?;
+        }
+        Ok(pulses)
+    }
+
+    /// Blocks that can be stored in a TAP file, in file order
+    pub fn tap_blocks(&self) -> Vec<Vec<u8>> {
+        self.blocks
+            .iter()
+            .filter_map(TzxBlock::tap_data)
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+}
+
+fn relative_index(index: usize, offset: i16) -> Option<usize> {
+    // A zero offset would jump to itself forever
+    if offset == 0 {
+        return None;
+    }
+    index.checked_add_signed(offset as isize)
+}
+
+// Reads before allocating, so a block length made up by a broken file fails
+// at the end of the data instead of exhausting memory
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated TZX block"));
+    }
+    Ok(data)
+}
+
+fn read_string<R: Read>(reader: &mut R, len: usize) -> io::Result<String> {
+    Ok(String::from_utf8_lossy(&read_vec(reader, len)?).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tap::tests::pause;
+
+    // A version 1.20 file made of the given blocks, each starting with its ID
+    fn tzx_bytes(blocks: &[&[u8]]) -> Vec<u8> {
+        [&SIGNATURE[..], &[1, 20]]
+            .into_iter()
+            .chain(blocks.iter().copied())
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    #[test]
+    fn blocks_are_read_in_file_order() {
+        let data = tzx_bytes(&[
+            &[0x10, 0xE8, 0x03, 3, 0, 0xFF, 0xAA, 0x55],
+            &[0x30, 5, b'h', b'e', b'l', b'l', b'o'],
+            &[0x20, 0, 0],
+            &[0x2A, 0, 0, 0, 0],
+        ]);
+        let tzx = Tzx::from_bytes(&mut data.as_slice()).unwrap();
+        assert_eq!((tzx.major, tzx.minor), (1, 20));
+        let ids: Vec<u8> = tzx.blocks.iter().map(TzxBlock::id).collect();
+        assert_eq!(ids, [0x10, 0x30, 0x20, 0x2A]);
+        assert!(matches!(
+            &tzx.blocks[0],
+            TzxBlock::StandardSpeed { pause: 1000, data } if data == &[0xFF, 0xAA, 0x55]
+        ));
+        assert!(matches!(&tzx.blocks[1], TzxBlock::Text(text) if text == "hello"));
+        assert!(tzx.blocks[2].stops_tape() && tzx.blocks[3].stops_tape());
+        assert_eq!(tzx.tap_blocks(), [vec![0xFF, 0xAA, 0x55]]);
+    }
+
+    #[test]
+    fn playback_follows_loops_calls_and_jumps() {
+        let tzx = Tzx {
+            major: 1,
+            minor: 20,
+            blocks: vec![
+                TzxBlock::LoopStart(2),
+                TzxBlock::Pause(10),
+                TzxBlock::LoopEnd,
+                TzxBlock::CallSequence(vec![2, 4]),
+                TzxBlock::Jump(5),
+                TzxBlock::Pause(20),
+                TzxBlock::Return,
+                TzxBlock::Pause(30),
+                TzxBlock::Return,
+                TzxBlock::Text("end".to_string()),
+                TzxBlock::Pause(40),
+                TzxBlock::Jump(0),
+                TzxBlock::Pause(50),
+            ],
+        };
+        assert_eq!(tzx.playback_order(), [1, 1, 5, 7, 9, 10]);
+    }
+
+    #[test]
+    fn pause_override_leaves_blocks_without_pauses_and_pause_blocks() {
+        let options = PulseOptions {
+            pause: Some(250),
+            ..PulseOptions::default()
+        };
+        let pulses = |block: TzxBlock| {
+            let mut pulses = Vec::new();
+            block.append_pulses(&mut pulses, &options).unwrap();
+            pulses
+        };
+
+        let paused = pulses(TzxBlock::StandardSpeed {
+            pause: 1000,
+            data: vec![0xFF, 1],
+        });
+        assert!(paused.ends_with(&pause(250)));
+        let running = pulses(TzxBlock::StandardSpeed {
+            pause: 0,
+            data: vec![0xFF, 1],
+        });
+        assert_eq!(running.last(), Some(&Pulse::Edge(pulse::ONE_PULSE)));
+        assert_eq!(pulses(TzxBlock::Pause(2000)), pause(2000));
+    }
+
+    #[test]
+    fn lengths_beyond_the_data_fail_without_allocating() {
+        let mut block = vec![0x35];
+        block.extend(b"custom info     ");
+        block.extend(0xFFFF_FFF0u32.to_le_bytes());
+        block.extend(b"data");
+        let data = tzx_bytes(&[&block]);
+        let error = Tzx::from_bytes(&mut data.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
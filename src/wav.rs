@@ -0,0 +1,146 @@
+//! Rendering tapes to PCM WAV files
+
+use crate::pulse::{self, Pulse, SampleOptions};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::{self, BufWriter, Error, ErrorKind, Seek, Write};
+use std::path::Path;
+
+/// Renders the pulses of a whole tape into a mono PCM WAV stream
+pub fn write_wav<W: Write + Seek>(
+    writer: W,
+    pulses: &[Pulse],
+    options: &SampleOptions,
+) -> io::Result<()> {
+    options.validate()?;
+
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: options.sample_rate,
+        bits_per_sample: options.bits_per_sample,
+        sample_format: SampleFormat::Int,
+    };
+
+    // Render the whole tape in one pass so the sample positions of later blocks
+    // don't pick up the rounding of earlier ones
+    let samples = pulse::pulses_to_samples(pulses, options);
+
+    // The samples are already whole steps of the bit depth, hound stores
+    // them in the smallest container that holds that many bits
+    let max = options.max_sample() as f64;
+    let mut wav_writer = WavWriter::new(writer, spec).map_err(to_io_error)?;
+    for sample in samples {
+        wav_writer
+            .write_sample((sample as f64 * max).round() as i32)
+            .map_err(to_io_error)?;
+    }
+    wav_writer.finalize().map_err(to_io_error)
+}
+
+/// Writes `pulses` to a WAV file
+pub fn export_wav<P: AsRef<Path>>(
+    path: P,
+    pulses: &[Pulse],
+    options: &SampleOptions,
+) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    write_wav(writer, pulses, options)
+}
+
+pub(crate) fn to_io_error(error: hound::Error) -> Error {
+    match error {
+        hound::Error::IoError(error) => error,
+        error => Error::new(ErrorKind::InvalidData, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pulse::{PulseOptions, CPU_CLOCK};
+    use std::io::Cursor;
+
+    // The level of every sample worked out on its own: sample n plays the
+    // pulse during which the tape passes n / rate seconds
+    fn expected_data(pulses: &[Pulse], sample_rate: u32) -> Vec<u8> {
+        let ends: Vec<(u64, bool)> = pulse::pulse_levels(pulses, sample_rate)
+            .map(|(level, end)| (end, level))
+            .collect();
+
+        let total = ends.last().map_or(0, |&(end, _)| end);
+        let mut data = Vec::new();
+        let mut pulse = 0;
+        for sample in 0..total {
+            while ends[pulse].0 <= sample {
+                pulse += 1;
+            }
+            let value: i16 = if ends[pulse].1 { i16::MAX } else { -i16::MAX };
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data
+    }
+
+    fn render(pulses: &[Pulse], sample_rate: u32) -> Vec<u8> {
+        let options = SampleOptions {
+            sample_rate,
+            ..SampleOptions::default()
+        };
+        let mut wav = Cursor::new(Vec::new());
+        write_wav(&mut wav, pulses, &options).unwrap();
+        wav.into_inner()
+    }
+
+    fn data_chunk(wav: &[u8]) -> &[u8] {
+        let start = wav.windows(4).position(|chunk| chunk == b"data").unwrap();
+        let len = u32::from_le_bytes(wav[start + 4..start + 8].try_into().unwrap()) as usize;
+        &wav[start + 8..start + 8 + len]
+    }
+
+    fn known_block() -> Vec<Pulse> {
+        let mut pulses = pulse::block_pulses(&[0xFF, 0xA5, 0x5A, 0xFF], &PulseOptions::default());
+        pulse::pause_pulses(2, &mut pulses);
+        pulses
+    }
+
+    #[test]
+    fn block_renders_exactly() {
+        let pulses = known_block();
+        for sample_rate in [44100, 48000] {
+            let wav = render(&pulses, sample_rate);
+            let spec = hound::WavReader::new(wav.as_slice()).unwrap().spec();
+            assert_eq!(spec.sample_rate, sample_rate);
+            assert_eq!(spec.channels, 1);
+            assert_eq!(
+                data_chunk(&wav),
+                expected_data(&pulses, sample_rate).as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn length_follows_the_tape_without_drift() {
+        let pulses = known_block();
+        let tstates: u64 = pulses
+            .iter()
+            .map(|&pulse| match pulse {
+                Pulse::Edge(duration) | Pulse::Hold(duration) | Pulse::Level(_, duration) => {
+                    duration as u64
+                }
+            })
+            .sum();
+        for sample_rate in [22050, 44100, 48000, 96000] {
+            let samples = data_chunk(&render(&pulses, sample_rate)).len() as u64 / 2;
+            assert_eq!(samples, tstates * sample_rate as u64 / CPU_CLOCK as u64);
+        }
+    }
+
+    #[test]
+    fn unsupported_bit_depths_are_rejected() {
+        let options = SampleOptions {
+            bits_per_sample: 12,
+            ..SampleOptions::default()
+        };
+        let mut wav = Cursor::new(Vec::new());
+        assert!(write_wav(&mut wav, &known_block(), &options).is_err());
+    }
+}